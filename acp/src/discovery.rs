@@ -0,0 +1,253 @@
+//! 发现本机运行中的应用
+//!
+//! 应用启动 ACP 服务器后在发现目录中写入 `<应用名>-<pid>.json`，
+//! 记录实际监听的地址，退出时删除。客户端读取该目录列出可以连接的应用，
+//! 因此应用可以监听端口 0，由系统分配空闲端口。
+//! 要求认证的应用还会在同一目录写入只有当前用户可以读取的 `<应用名>-<pid>.token`。
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// 发现目录中的一个应用实例
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppEntry {
+    pub app_name: String,
+    pub pid: u32,
+    /// 客户端可以直接连接的地址: `主机:端口`、`ws://主机:端口/` 或 `unix:<路径>`
+    pub addresses: Vec<String>,
+}
+
+impl AppEntry {
+    /// 发现文件的文件名
+    pub fn file_name(&self) -> String {
+        format!("{}-{}.json", self.app_name, self.pid)
+    }
+
+    /// 首选的连接地址。Unix 套接字只有当前用户可以连接，优先使用，其次是 TCP
+    pub fn preferred_address(&self) -> Option<&str> {
+        let rank = |addr: &str| {
+            if addr.starts_with("unix:") {
+                0
+            } else if addr.starts_with("ws://") {
+                2
+            } else {
+                1
+            }
+        };
+        self.addresses
+            .iter()
+            .min_by_key(|addr| rank(addr))
+            .map(String::as_str)
+    }
+
+    /// 认证令牌文件的文件名
+    pub fn token_file_name(&self) -> String {
+        format!("{}-{}.token", self.app_name, self.pid)
+    }
+
+    /// 是否与用户给出的应用名或 pid 相符
+    pub fn matches(&self, selector: &str) -> bool {
+        self.app_name == selector || self.pid.to_string() == selector
+    }
+
+    /// 写入发现目录，返回发现文件的路径。
+    /// 先写临时文件再重命名，读取方不会看到写了一半的文件
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        create_private_dir(dir)?;
+
+        let path = dir.join(self.file_name());
+        let temp_path = dir.join(format!(".{}.tmp", self.file_name()));
+        let contents = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(path)
+    }
+
+    /// 把认证令牌写入发现目录，文件只有当前用户可以读取
+    pub fn write_token(&self, dir: &Path, token: &str) -> io::Result<PathBuf> {
+        create_private_dir(dir)?;
+        let path = dir.join(self.token_file_name());
+        // 先删除可能遗留的文件，保证新文件以 0600 创建，不存在可被其他用户读取的窗口
+        let _ = fs::remove_file(&path);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&path)?.write_all(token.as_bytes())?;
+        Ok(path)
+    }
+
+    /// 读取应用的认证令牌，应用不要求认证或文件不可读时为 `None`
+    pub fn read_token(&self, dir: &Path) -> Option<String> {
+        let token = fs::read_to_string(dir.join(self.token_file_name())).ok()?;
+        let token = token.trim();
+        (!token.is_empty()).then(|| token.to_string())
+    }
+}
+
+/// 发现目录 `$XDG_RUNTIME_DIR/agentkit`，未设置 `XDG_RUNTIME_DIR` 时使用系统临时目录下的 `agentkit`
+pub fn discovery_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("agentkit")
+}
+
+/// 创建发现目录并设为只有当前用户可以访问。
+/// 目录属于其他用户时无法修改权限，返回错误
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// 列出发现目录中仍在运行的应用，按 pid 排序。
+/// 无法解析的文件、已退出进程遗留的文件和不属于当前用户的文件会被跳过
+pub fn list_apps(dir: &Path) -> Vec<AppEntry> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut apps: Vec<AppEntry> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| is_owned_by_current_user(path))
+        .filter_map(|path| fs::read(path).ok())
+        .filter_map(|contents| serde_json::from_slice::<AppEntry>(&contents).ok())
+        .filter(|app| is_process_alive(app.pid))
+        .collect();
+    apps.sort_by_key(|app| app.pid);
+    apps
+}
+
+/// 文件是否属于当前用户。发现目录可能位于共享的临时目录中，
+/// 其他用户放置的发现文件可以把客户端引向他们监听的地址
+#[cfg(unix)]
+fn is_owned_by_current_user(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // SAFETY: geteuid 没有前置条件，总是成功
+    let uid = unsafe { libc::geteuid() };
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.uid() == uid)
+}
+
+#[cfg(not(unix))]
+fn is_owned_by_current_user(_path: &Path) -> bool {
+    true
+}
+
+/// 进程是否仍在运行。只有 Linux 可以不借助额外依赖判断，其他平台一律视为在运行
+fn is_process_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("acp-discovery-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn written_entries_are_listed() {
+        let dir = temp_dir("list");
+        let app = AppEntry {
+            app_name: "target_gpui_app".to_string(),
+            pid: std::process::id(),
+            addresses: vec!["127.0.0.1:40123".to_string()],
+        };
+        let path = app.write(&dir).unwrap();
+        assert_eq!(path.file_name().unwrap().to_str().unwrap(), app.file_name());
+
+        // 无法解析的文件被跳过
+        fs::write(dir.join("broken-1.json"), "{").unwrap();
+
+        assert_eq!(list_apps(&dir), vec![app]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn token_file_is_private() {
+        let dir = temp_dir("token");
+        let app = AppEntry {
+            app_name: "target_gpui_app".to_string(),
+            pid: std::process::id(),
+            addresses: Vec::new(),
+        };
+        assert_eq!(app.read_token(&dir), None);
+
+        let path = app.write_token(&dir, "3f9a").unwrap();
+        app.write_token(&dir, "c0de").unwrap();
+        assert_eq!(app.read_token(&dir).as_deref(), Some("c0de"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+            assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn entries_of_other_users_are_skipped() {
+        let dir = temp_dir("owner");
+        let app = AppEntry {
+            app_name: "target_gpui_app".to_string(),
+            pid: std::process::id(),
+            addresses: vec!["127.0.0.1:40123".to_string()],
+        };
+        let path = app.write(&dir).unwrap();
+        assert_eq!(list_apps(&dir), vec![app]);
+
+        // 只有 root 可以把文件交给其他用户，其他情况下跳过
+        if std::os::unix::fs::chown(&path, Some(65534), None).is_ok() {
+            assert!(list_apps(&dir).is_empty());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_directory_lists_nothing() {
+        assert!(list_apps(&temp_dir("missing")).is_empty());
+    }
+
+    #[test]
+    fn unix_socket_is_preferred() {
+        let app = AppEntry {
+            app_name: "target_gpui_app".to_string(),
+            pid: 42,
+            addresses: vec![
+                "ws://127.0.0.1:7881/".to_string(),
+                "127.0.0.1:7880".to_string(),
+                "unix:/run/user/1000/agentkit/target_gpui_app-42.sock".to_string(),
+            ],
+        };
+        assert_eq!(
+            app.preferred_address(),
+            Some("unix:/run/user/1000/agentkit/target_gpui_app-42.sock")
+        );
+        assert!(app.matches("42"));
+        assert!(app.matches("target_gpui_app"));
+        assert!(!app.matches("other_app"));
+    }
+}
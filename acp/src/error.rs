@@ -0,0 +1,142 @@
+//! 结构化的错误信息
+//!
+//! 失败的响应除了给人看的 `message`，还带有稳定的错误码，
+//! 客户端可以据此决定重试、重新规划或提示用户。
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// 错误码，线上格式为 snake_case 字符串
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// 消息不是合法的 JSON 或 UTF-8
+    ParseError,
+    /// 消息不是请求，或请求不能在当前上下文中执行
+    InvalidRequest,
+    /// 请求参数不合法
+    InvalidParams,
+    /// 协议版本不兼容
+    IncompatibleVersion,
+    /// 连接尚未认证，或握手中的认证令牌不正确
+    Unauthorized,
+    /// 命令未注册
+    UnknownCommand,
+    /// 元素不存在或没有元素满足查询
+    UnknownElement,
+    /// 有多个元素满足查询
+    AmbiguousTarget,
+    /// 元素不支持该操作
+    UnsupportedAction,
+    /// 元素当前不可用
+    ElementDisabled,
+    /// 界面尚未渲染
+    NotRendered,
+    /// 命令或元素的处理函数返回了错误
+    HandlerFailed,
+    /// 服务端繁忙，例如连接数已达上限
+    ServerBusy,
+    /// 请求被客户端取消
+    Cancelled,
+    /// 请求超过 `timeout_ms` 仍未完成
+    TimedOut,
+    /// 新版本服务端使用了本版本不认识的错误码
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// 错误码的线上名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::ParseError => "parse_error",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::InvalidParams => "invalid_params",
+            ErrorCode::IncompatibleVersion => "incompatible_version",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::UnknownElement => "unknown_element",
+            ErrorCode::AmbiguousTarget => "ambiguous_target",
+            ErrorCode::UnsupportedAction => "unsupported_action",
+            ErrorCode::ElementDisabled => "element_disabled",
+            ErrorCode::NotRendered => "not_rendered",
+            ErrorCode::HandlerFailed => "handler_failed",
+            ErrorCode::ServerBusy => "server_busy",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::TimedOut => "timed_out",
+            ErrorCode::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 失败响应中的错误对象
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AcpError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// 可选的补充信息，例如可用的命令或出错字段的 JSON 路径
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl AcpError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// 附带补充信息
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl fmt::Display for AcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for AcpError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn codes_use_snake_case_names() {
+        for code in [
+            ErrorCode::InvalidRequest,
+            ErrorCode::UnknownCommand,
+            ErrorCode::AmbiguousTarget,
+            ErrorCode::Unauthorized,
+            ErrorCode::ServerBusy,
+            ErrorCode::TimedOut,
+        ] {
+            assert_eq!(serde_json::to_value(code).unwrap(), json!(code.as_str()));
+        }
+    }
+
+    #[test]
+    fn unknown_codes_are_preserved_as_unknown() {
+        let error: AcpError = serde_json::from_value(json!({
+            "code": "rate_limited",
+            "message": "请求过于频繁"
+        }))
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::Unknown);
+        assert_eq!(error.details, None);
+    }
+}
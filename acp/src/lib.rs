@@ -0,0 +1,1169 @@
+//! 应用控制协议 (ACP) 的共享消息定义
+//!
+//! `target_gpui_app` 和 `agentkit_layer` 都依赖这个 crate，
+//! 协议格式的说明见 `PROTOCOL.md`。
+
+pub mod discovery;
+mod error;
+pub mod query;
+pub mod schema;
+
+pub use accesskit;
+pub use error::{AcpError, ErrorCode};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// 当前协议版本
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 仍然兼容的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// 未指定地址时应用监听、客户端连接的 TCP 地址
+pub const DEFAULT_TCP_ADDR: &str = "127.0.0.1:7880";
+
+/// ACP 消息，按 `type` 字段区分请求、响应和事件
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AcpMessage {
+    /// 客户端发给应用的请求。设置了 `timeout_ms` 时，超过该时间仍未完成的请求以 `timed_out` 失败
+    Request {
+        seq_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_ms: Option<u64>,
+        payload: RequestPayload,
+    },
+    /// 应用对请求的响应，`seq_id` 与请求相同
+    Response { seq_id: u64, payload: ResponsePayload },
+    /// 应用主动推送的事件
+    Event { seq_id: u64, payload: EventPayload },
+}
+
+impl AcpMessage {
+    /// 获取消息的序列 ID
+    pub fn seq_id(&self) -> u64 {
+        match self {
+            AcpMessage::Request { seq_id, .. }
+            | AcpMessage::Response { seq_id, .. }
+            | AcpMessage::Event { seq_id, .. } => *seq_id,
+        }
+    }
+
+    /// 序列化为一行 JSON（带结尾换行符）
+    pub fn to_line(&self) -> serde_json::Result<String> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        Ok(line)
+    }
+
+    /// 从一行 JSON 解析消息
+    pub fn from_line(line: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(line.trim_end())
+    }
+
+    /// 从一行 JSON 解析消息，失败时给出可以直接回复给对端的错误，
+    /// 并尽量找回消息的 `seq_id`
+    pub fn parse_line(line: &str) -> Result<Self, InvalidMessage> {
+        let value: Value = serde_json::from_str(line.trim_end()).map_err(|e| InvalidMessage {
+            seq_id: None,
+            error: AcpError::new(ErrorCode::ParseError, format!("JSON 格式错误: {}", e))
+                .with_details(json!({ "line": e.line(), "column": e.column() })),
+        })?;
+
+        let seq_id = value.get("seq_id").and_then(Value::as_u64);
+        serde_json::from_value(value.clone()).map_err(|e| {
+            let mut error = AcpError::new(ErrorCode::InvalidRequest, format!("消息结构错误: {}", e));
+            if let Some(path) = locate_invalid_field(&value) {
+                error = error.with_details(json!({ "path": path }));
+            }
+            InvalidMessage { seq_id, error }
+        })
+    }
+}
+
+/// 无法解析的消息
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidMessage {
+    /// 从消息中找回的 `seq_id`，找不到时为 `None`
+    pub seq_id: Option<u64>,
+    pub error: AcpError,
+}
+
+/// 找出结构错误的消息中出错字段的路径，消息不是对象时为 `None`。
+/// 逐个检查字段而不依赖 serde 的错误文本，错误文本的措辞可能随版本变化
+fn locate_invalid_field(value: &Value) -> Option<String> {
+    let message = value.as_object()?;
+    let message_type = message.get("type").and_then(Value::as_str);
+    if !matches!(message_type, Some("request" | "response" | "event")) {
+        return Some("type".to_string());
+    }
+    if !message.get("seq_id").is_some_and(Value::is_u64) {
+        return Some("seq_id".to_string());
+    }
+    let timeout_ms = message.get("timeout_ms").filter(|timeout| !timeout.is_null());
+    if message_type == Some("request") && timeout_ms.is_some_and(|timeout| !timeout.is_u64()) {
+        return Some("timeout_ms".to_string());
+    }
+    let payload = match message.get("payload") {
+        Some(payload) if payload.is_object() => payload,
+        _ => return Some("payload".to_string()),
+    };
+
+    if message_type == Some("request") {
+        return Some(locate_invalid_request_field(payload, "payload"));
+    }
+    Some("payload".to_string())
+}
+
+/// 找出请求载荷中出错字段的路径，`prefix` 为载荷本身的路径
+fn locate_invalid_request_field(payload: &Value, prefix: &str) -> String {
+    let Some(fields) = payload.as_object() else {
+        return prefix.to_string();
+    };
+    let action = format!("{}.action", prefix);
+    let Some(required) = fields
+        .get("action")
+        .and_then(Value::as_str)
+        .and_then(RequestPayload::required_fields)
+    else {
+        return action;
+    };
+    if let Some(missing) = required.iter().find(|field| !fields.contains_key(**field)) {
+        return format!("{}.{}", prefix, missing);
+    }
+
+    // 批处理中出错的请求
+    if let Some(requests) = fields.get("requests").and_then(Value::as_array) {
+        let invalid = requests
+            .iter()
+            .enumerate()
+            .find(|(_, request)| RequestPayload::deserialize(*request).is_err());
+        if let Some((index, request)) = invalid {
+            let prefix = format!("{}.requests[{}]", prefix, index);
+            return locate_invalid_request_field(request, &prefix);
+        }
+    }
+
+    // 去掉某个可选字段后能够解析时，该字段的值有误
+    let invalid_optional = fields
+        .keys()
+        .filter(|field| *field != "action" && !required.contains(&field.as_str()))
+        .find(|field| {
+            let mut without = fields.clone();
+            without.remove(*field);
+            serde_json::from_value::<RequestPayload>(Value::Object(without)).is_ok()
+        });
+    match invalid_optional {
+        Some(field) => format!("{}.{}", prefix, field),
+        None => prefix.to_string(),
+    }
+}
+
+/// 请求载荷，按 `action` 字段区分
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RequestPayload {
+    /// 握手请求，连接建立后由客户端首先发送
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        client_version: String,
+        /// 应用启动时生成的认证令牌，应用要求认证时必须提供
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// 执行应用注册的自定义命令
+    CustomCommand {
+        command_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        params: Option<Value>,
+    },
+    /// 列出应用暴露的全部命令
+    ListCommands,
+    /// 获取界面元素树
+    GetUiTree,
+    /// 点击元素
+    Click {
+        #[serde(flatten)]
+        target: ElementTarget,
+    },
+    /// 让元素获得焦点
+    Focus {
+        #[serde(flatten)]
+        target: ElementTarget,
+    },
+    /// 设置元素的值，例如输入框的文本
+    SetValue {
+        #[serde(flatten)]
+        target: ElementTarget,
+        value: Value,
+    },
+    /// 切换元素的选中状态，例如复选框
+    Toggle {
+        #[serde(flatten)]
+        target: ElementTarget,
+    },
+    /// 滚动元素，单位为逻辑像素
+    Scroll {
+        #[serde(flatten)]
+        target: ElementTarget,
+        #[serde(default)]
+        dx: f32,
+        #[serde(default)]
+        dy: f32,
+    },
+    /// 获取 AccessKit 格式的无障碍树
+    GetAccessibilityTree,
+    /// 执行 AccessKit 操作请求，效果与用户直接操作界面相同
+    AccessibilityAction { request: accesskit::ActionRequest },
+    /// 订阅应用事件，`events` 为空表示订阅全部事件。
+    /// 之后推送的事件以本请求的 `seq_id` 作为订阅 ID
+    Subscribe {
+        #[serde(default)]
+        events: Vec<String>,
+    },
+    /// 取消订阅
+    Unsubscribe { subscription_id: u64 },
+    /// 取消同一连接上仍在执行或排队的请求，被取消的请求以 `cancelled` 失败
+    Cancel { target_seq_id: u64 },
+    /// 心跳，应用立即回复，不必等待前面的请求执行完毕
+    Ping,
+    /// 按顺序执行多个请求，每个请求都有各自的结果
+    Batch {
+        requests: Vec<RequestPayload>,
+        /// 为 `true` 时任一请求失败即停止，并把应用状态回滚到批处理开始之前
+        #[serde(default)]
+        atomic: bool,
+    },
+}
+
+impl RequestPayload {
+    /// 获取请求的 `action` 名称
+    pub fn action_name(&self) -> &'static str {
+        match self {
+            RequestPayload::Hello { .. } => "hello",
+            RequestPayload::CustomCommand { .. } => "custom_command",
+            RequestPayload::ListCommands => "list_commands",
+            RequestPayload::GetUiTree => "get_ui_tree",
+            RequestPayload::Click { .. } => "click",
+            RequestPayload::Focus { .. } => "focus",
+            RequestPayload::SetValue { .. } => "set_value",
+            RequestPayload::Toggle { .. } => "toggle",
+            RequestPayload::Scroll { .. } => "scroll",
+            RequestPayload::GetAccessibilityTree => "get_accessibility_tree",
+            RequestPayload::AccessibilityAction { .. } => "accessibility_action",
+            RequestPayload::Subscribe { .. } => "subscribe",
+            RequestPayload::Unsubscribe { .. } => "unsubscribe",
+            RequestPayload::Cancel { .. } => "cancel",
+            RequestPayload::Ping => "ping",
+            RequestPayload::Batch { .. } => "batch",
+        }
+    }
+
+    /// `action` 对应的请求必须提供的其他字段，未知的 `action` 为 `None`
+    fn required_fields(action: &str) -> Option<&'static [&'static str]> {
+        let fields: &[&str] = match action {
+            "hello" => &["protocol_version", "client_name", "client_version"],
+            "custom_command" => &["command_name"],
+            "list_commands" | "get_ui_tree" | "get_accessibility_tree" | "ping" => &[],
+            "click" | "focus" | "toggle" | "scroll" | "subscribe" => &[],
+            "set_value" => &["value"],
+            "accessibility_action" => &["request"],
+            "unsubscribe" => &["subscription_id"],
+            "cancel" => &["target_seq_id"],
+            "batch" => &["requests"],
+            _ => return None,
+        };
+        Some(fields)
+    }
+
+    /// 能否放在 `batch` 中执行。握手、订阅、取消和心跳与连接绑定，批处理不能嵌套
+    pub fn can_batch(&self) -> bool {
+        !matches!(
+            self,
+            RequestPayload::Hello { .. }
+                | RequestPayload::Subscribe { .. }
+                | RequestPayload::Unsubscribe { .. }
+                | RequestPayload::Cancel { .. }
+                | RequestPayload::Ping
+                | RequestPayload::Batch { .. }
+        )
+    }
+
+    /// 元素操作请求的目标元素，其他请求为 `None`
+    pub fn target(&self) -> Option<&ElementTarget> {
+        match self {
+            RequestPayload::Click { target }
+            | RequestPayload::Focus { target }
+            | RequestPayload::SetValue { target, .. }
+            | RequestPayload::Toggle { target }
+            | RequestPayload::Scroll { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+}
+
+/// 元素操作的目标，`element_id` 和 `target_query` 必须恰好提供一个
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ElementTarget {
+    /// 元素 ID，见 `UiElement::id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub element_id: Option<String>,
+    /// 元素查询，语法见 [`query`] 模块
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_query: Option<String>,
+}
+
+impl ElementTarget {
+    /// 按 ID 定位元素
+    pub fn id(element_id: impl Into<String>) -> Self {
+        Self {
+            element_id: Some(element_id.into()),
+            target_query: None,
+        }
+    }
+
+    /// 按查询定位元素
+    pub fn query(target_query: impl Into<String>) -> Self {
+        Self {
+            element_id: None,
+            target_query: Some(target_query.into()),
+        }
+    }
+
+    /// 在元素树中解析出目标元素
+    pub fn resolve<'a>(&self, root: &'a UiElement) -> Result<&'a UiElement, query::QueryError> {
+        match (&self.element_id, &self.target_query) {
+            (Some(id), None) => root
+                .find(id)
+                .ok_or_else(|| query::QueryError::NotFound(format!("id={}", id))),
+            (None, Some(target_query)) => query::resolve(root, target_query),
+            _ => Err(query::QueryError::Parse(
+                "element_id 和 target_query 必须恰好提供一个".to_string(),
+            )),
+        }
+    }
+}
+
+/// 响应载荷
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponsePayload {
+    pub success: bool,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ResponseData>,
+    /// 失败时的错误对象，旧版本服务端没有该字段
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<AcpError>,
+}
+
+impl ResponsePayload {
+    /// 成功响应
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            success: true,
+            message: message.into(),
+            data: None,
+            error: None,
+        }
+    }
+
+    /// 失败响应，`message` 为 "错误: " 加上错误说明
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::failure(AcpError::new(code, message))
+    }
+
+    /// 带错误对象的失败响应
+    pub fn failure(error: AcpError) -> Self {
+        Self {
+            success: false,
+            message: format!("错误: {}", error.message),
+            data: None,
+            error: Some(error),
+        }
+    }
+
+    /// 附带结构化数据
+    pub fn with_data(mut self, data: ResponseData) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+/// 响应中的结构化数据，按 `kind` 字段区分
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ResponseData {
+    /// 对 `hello` 的应答
+    Welcome(Welcome),
+    /// 对 `list_commands` 的应答
+    Commands { commands: Vec<CommandInfo> },
+    /// 对 `get_ui_tree` 的应答
+    UiTree { root: UiElement },
+    /// 对 `get_accessibility_tree` 的应答，包含完整的树
+    AccessibilityTree { update: accesskit::TreeUpdate },
+    /// 对 `batch` 的应答，按顺序对应已执行的请求
+    Batch { results: Vec<ResponsePayload> },
+}
+
+/// 握手应答，描述应用及其支持的能力
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Welcome {
+    /// 协商后的协议版本
+    pub protocol_version: u32,
+    pub app_name: String,
+    pub app_version: String,
+    /// 支持的 `action` 列表
+    pub actions: Vec<String>,
+    /// 支持的 `command_name` 列表
+    pub commands: Vec<String>,
+    /// 可以订阅的事件列表，旧版本服务端没有该字段
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl Welcome {
+    /// 是否支持指定的 `action`
+    pub fn supports_action(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == action)
+    }
+
+    /// 是否支持指定的命令
+    pub fn supports_command(&self, command_name: &str) -> bool {
+        self.commands.iter().any(|c| c == command_name)
+    }
+
+    /// 是否可以订阅指定的事件
+    pub fn supports_event(&self, event: &str) -> bool {
+        self.events.iter().any(|e| e == event)
+    }
+}
+
+/// 命令描述
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema
+    pub params_schema: Value,
+}
+
+/// 界面元素快照
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UiElement {
+    /// 元素 ID，在应用内唯一
+    pub id: String,
+    /// 元素角色，例如 `window`、`button`、`label`
+    pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// 元素显示的文本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    pub enabled: bool,
+    pub visible: bool,
+    /// 元素在窗口中的位置，尚未布局时为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounds: Option<ElementBounds>,
+    /// 元素支持的操作，例如 `click`
+    #[serde(default)]
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub children: Vec<UiElement>,
+}
+
+impl UiElement {
+    /// 创建可用且可见的元素
+    pub fn new(id: impl Into<String>, role: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            role: role.into(),
+            label: None,
+            text: None,
+            enabled: true,
+            visible: true,
+            bounds: None,
+            actions: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// 设置无障碍标签
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// 设置显示文本
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// 设置支持的操作
+    pub fn with_actions(mut self, actions: &[&str]) -> Self {
+        self.actions = actions.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// 设置子元素
+    pub fn with_children(mut self, children: Vec<UiElement>) -> Self {
+        self.children = children;
+        self
+    }
+
+    /// 按 ID 查找元素（包括自身）
+    pub fn find(&self, id: &str) -> Option<&UiElement> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(id))
+    }
+
+    /// 按 ID 查找元素的可变引用（包括自身）
+    pub fn find_mut(&mut self, id: &str) -> Option<&mut UiElement> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|child| child.find_mut(id))
+    }
+}
+
+/// 元素在窗口坐标系中的矩形区域，单位为逻辑像素
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ElementBounds {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// 事件载荷，按 `event` 字段区分
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventPayload {
+    /// 应用状态发生了变化
+    StateChanged { state: Value },
+    /// 界面中新增了元素
+    ElementAdded {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parent_id: Option<String>,
+        element: UiElement,
+    },
+    /// 焦点移动到了另一个元素，`element_id` 为空表示没有元素获得焦点
+    FocusChanged {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        element_id: Option<String>,
+    },
+    /// 窗口已关闭
+    WindowClosed,
+}
+
+impl EventPayload {
+    /// 获取事件名称，与 `subscribe` 请求中的名称一致
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            EventPayload::StateChanged { .. } => "state_changed",
+            EventPayload::ElementAdded { .. } => "element_added",
+            EventPayload::FocusChanged { .. } => "focus_changed",
+            EventPayload::WindowClosed => "window_closed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip(message: &AcpMessage) {
+        let line = message.to_line().unwrap();
+        assert!(line.ends_with('\n'));
+        assert_eq!(&AcpMessage::from_line(&line).unwrap(), message);
+    }
+
+    #[test]
+    fn request_round_trip() {
+        round_trip(&AcpMessage::Request {
+            seq_id: 42,
+            timeout_ms: None,
+            payload: RequestPayload::CustomCommand {
+                command_name: "CYCLE_COLOR".to_string(),
+                params: None,
+            },
+        });
+        round_trip(&AcpMessage::Request {
+            seq_id: u64::MAX,
+            timeout_ms: None,
+            payload: RequestPayload::CustomCommand {
+                command_name: "CYCLE_COLOR".to_string(),
+                params: Some(json!({ "times": 2 })),
+            },
+        });
+    }
+
+    #[test]
+    fn response_round_trip() {
+        round_trip(&AcpMessage::Response {
+            seq_id: 7,
+            payload: ResponsePayload::ok("颜色已通过 ACP 循环"),
+        });
+        round_trip(&AcpMessage::Response {
+            seq_id: 8,
+            payload: ResponsePayload::failure(
+                AcpError::new(ErrorCode::UnknownCommand, "未知命令: FOO")
+                    .with_details(json!({ "available_commands": ["CYCLE_COLOR"] })),
+            ),
+        });
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        round_trip(&AcpMessage::Request {
+            seq_id: 1,
+            timeout_ms: None,
+            payload: RequestPayload::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_name: "agentkit_layer".to_string(),
+                client_version: "0.1.0".to_string(),
+                token: Some("3f9a".to_string()),
+            },
+        });
+        round_trip(&AcpMessage::Response {
+            seq_id: 1,
+            payload: ResponsePayload::ok("欢迎").with_data(ResponseData::Welcome(Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                app_name: "target_gpui_app".to_string(),
+                app_version: "0.1.0".to_string(),
+                actions: vec!["hello".to_string(), "custom_command".to_string()],
+                commands: vec!["CYCLE_COLOR".to_string()],
+                events: vec!["state_changed".to_string()],
+            })),
+        });
+
+        // 旧版本服务端的欢迎信息没有 events 字段
+        let legacy: Welcome = serde_json::from_value(json!({
+            "protocol_version": 1,
+            "app_name": "target_gpui_app",
+            "app_version": "0.1.0",
+            "actions": ["hello"],
+            "commands": []
+        }))
+        .unwrap();
+        assert!(!legacy.supports_event("state_changed"));
+
+        // 不要求认证的应用不需要令牌，字段可以省略
+        let hello = AcpMessage::from_line(
+            r#"{"type":"request","seq_id":2,"payload":{"action":"hello","protocol_version":1,"client_name":"cli","client_version":"0.1.0"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            hello,
+            AcpMessage::Request {
+                payload: RequestPayload::Hello { token: None, .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn list_commands_round_trip() {
+        round_trip(&AcpMessage::Request {
+            seq_id: 2,
+            timeout_ms: None,
+            payload: RequestPayload::ListCommands,
+        });
+        round_trip(&AcpMessage::Response {
+            seq_id: 2,
+            payload: ResponsePayload::ok("共 1 个命令").with_data(ResponseData::Commands {
+                commands: vec![CommandInfo {
+                    name: "CYCLE_COLOR".to_string(),
+                    description: "循环切换背景颜色".to_string(),
+                    params_schema: json!({ "type": "object", "properties": {} }),
+                }],
+            }),
+        });
+        assert_eq!(
+            serde_json::to_value(RequestPayload::ListCommands).unwrap(),
+            json!({ "action": "list_commands" })
+        );
+    }
+
+    #[test]
+    fn ui_tree_round_trip() {
+        let mut root = UiElement::new("root", "window")
+            .with_label("target_gpui_app")
+            .with_children(vec![UiElement::new("cycle_color_button", "button")
+                .with_text("点击按钮或通过ACP改变颜色")
+                .with_actions(&["click"])]);
+        root.bounds = Some(ElementBounds {
+            x: 0.0,
+            y: 0.0,
+            width: 800.0,
+            height: 600.0,
+        });
+        assert_eq!(root.find("cycle_color_button").unwrap().role, "button");
+        assert!(root.find("missing").is_none());
+
+        round_trip(&AcpMessage::Request {
+            seq_id: 4,
+            timeout_ms: None,
+            payload: RequestPayload::GetUiTree,
+        });
+        round_trip(&AcpMessage::Response {
+            seq_id: 4,
+            payload: ResponsePayload::ok("界面元素树").with_data(ResponseData::UiTree { root }),
+        });
+    }
+
+    #[test]
+    fn element_action_round_trip() {
+        let target = ElementTarget::id("cycle_color_button");
+        for payload in [
+            RequestPayload::Click {
+                target: target.clone(),
+            },
+            RequestPayload::Focus {
+                target: target.clone(),
+            },
+            RequestPayload::SetValue {
+                target: target.clone(),
+                value: json!("Light Green"),
+            },
+            RequestPayload::Toggle {
+                target: target.clone(),
+            },
+            RequestPayload::Scroll {
+                target: target.clone(),
+                dx: 0.0,
+                dy: -120.0,
+            },
+        ] {
+            assert_eq!(payload.target(), Some(&target));
+            round_trip(&AcpMessage::Request { seq_id: 5, timeout_ms: None, payload });
+        }
+    }
+
+    #[test]
+    fn element_action_wire_format() {
+        let payload = RequestPayload::SetValue {
+            target: ElementTarget::id("name_input"),
+            value: json!("AgentKit"),
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({ "action": "set_value", "element_id": "name_input", "value": "AgentKit" })
+        );
+
+        let scroll: RequestPayload =
+            serde_json::from_value(json!({ "action": "scroll", "element_id": "list", "dy": 40.0 }))
+                .unwrap();
+        assert_eq!(
+            scroll,
+            RequestPayload::Scroll {
+                target: ElementTarget::id("list"),
+                dx: 0.0,
+                dy: 40.0,
+            }
+        );
+    }
+
+    #[test]
+    fn element_target_by_query() {
+        let payload: RequestPayload = serde_json::from_value(json!({
+            "action": "click",
+            "target_query": "role=button name~\"color\""
+        }))
+        .unwrap();
+        assert_eq!(
+            payload.target(),
+            Some(&ElementTarget::query(r#"role=button name~"color""#))
+        );
+
+        let root = UiElement::new("root", "window").with_children(vec![
+            UiElement::new("cycle_color_button", "button").with_text("Change color"),
+        ]);
+        let target = payload.target().unwrap();
+        assert_eq!(target.resolve(&root).unwrap().id, "cycle_color_button");
+        assert_eq!(
+            ElementTarget::id("cycle_color_button").resolve(&root).unwrap().id,
+            "cycle_color_button"
+        );
+        assert!(ElementTarget::id("missing").resolve(&root).is_err());
+
+        let neither = ElementTarget {
+            element_id: None,
+            target_query: None,
+        };
+        assert!(matches!(
+            neither.resolve(&root),
+            Err(query::QueryError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn accessibility_round_trip() {
+        use accesskit::{Action, ActionRequest, Node, NodeId, Role, Tree, TreeUpdate};
+
+        let mut button = Node::new(Role::Button);
+        button.set_label("循环背景颜色");
+        button.add_action(Action::Click);
+        let mut window = Node::new(Role::Window);
+        window.set_children(vec![NodeId(2)]);
+
+        round_trip(&AcpMessage::Response {
+            seq_id: 9,
+            payload: ResponsePayload::ok("无障碍树").with_data(ResponseData::AccessibilityTree {
+                update: TreeUpdate {
+                    nodes: vec![(NodeId(1), window), (NodeId(2), button)],
+                    tree: Some(Tree::new(NodeId(1))),
+                    focus: NodeId(1),
+                },
+            }),
+        });
+        round_trip(&AcpMessage::Request {
+            seq_id: 10,
+            timeout_ms: None,
+            payload: RequestPayload::AccessibilityAction {
+                request: ActionRequest {
+                    action: Action::Click,
+                    target: NodeId(2),
+                    data: None,
+                },
+            },
+        });
+        round_trip(&AcpMessage::Request {
+            seq_id: 11,
+            timeout_ms: None,
+            payload: RequestPayload::GetAccessibilityTree,
+        });
+    }
+
+    #[test]
+    fn response_without_data_omits_field() {
+        let value = serde_json::to_value(ResponsePayload::ok("完成")).unwrap();
+        assert_eq!(value, json!({ "success": true, "message": "完成" }));
+    }
+
+    #[test]
+    fn event_round_trip() {
+        round_trip(&AcpMessage::Event {
+            seq_id: 1,
+            payload: EventPayload::StateChanged {
+                state: json!({ "bg_color": "Light Blue" }),
+            },
+        });
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let batch: RequestPayload = serde_json::from_value(json!({
+            "action": "batch",
+            "requests": [
+                { "action": "custom_command", "command_name": "CYCLE_COLOR" },
+                { "action": "click", "element_id": "cycle_color_button" }
+            ]
+        }))
+        .unwrap();
+        let RequestPayload::Batch { requests, atomic } = &batch else {
+            panic!("应解析为 batch: {:?}", batch);
+        };
+        assert!(!atomic);
+        assert!(requests.iter().all(RequestPayload::can_batch));
+        assert!(!batch.can_batch());
+        round_trip(&AcpMessage::Request { seq_id: 20, timeout_ms: None, payload: batch });
+
+        round_trip(&AcpMessage::Response {
+            seq_id: 20,
+            payload: ResponsePayload::failure(
+                AcpError::new(ErrorCode::UnknownElement, "批处理第 2 项失败")
+                    .with_details(json!({ "index": 1, "rolled_back": true })),
+            )
+            .with_data(ResponseData::Batch {
+                results: vec![
+                    ResponsePayload::ok("颜色已通过 ACP 循环"),
+                    ResponsePayload::error(ErrorCode::UnknownElement, "元素不存在: missing"),
+                ],
+            }),
+        });
+    }
+
+    #[test]
+    fn cancel_and_timeout_round_trip() {
+        let message = AcpMessage::Request {
+            seq_id: 21,
+            timeout_ms: Some(1500),
+            payload: RequestPayload::CustomCommand {
+                command_name: "CYCLE_COLOR".to_string(),
+                params: None,
+            },
+        };
+        round_trip(&message);
+        assert_eq!(serde_json::to_value(&message).unwrap()["timeout_ms"], json!(1500));
+
+        let cancel = RequestPayload::Cancel { target_seq_id: 21 };
+        assert!(!cancel.can_batch());
+        assert_eq!(
+            serde_json::to_value(&cancel).unwrap(),
+            json!({ "action": "cancel", "target_seq_id": 21 })
+        );
+        round_trip(&AcpMessage::Request {
+            seq_id: 22,
+            timeout_ms: None,
+            payload: cancel,
+        });
+        round_trip(&AcpMessage::Response {
+            seq_id: 21,
+            payload: ResponsePayload::error(ErrorCode::Cancelled, "请求已取消"),
+        });
+    }
+
+    #[test]
+    fn ping_wire_format() {
+        let message = AcpMessage::Request {
+            seq_id: 23,
+            timeout_ms: None,
+            payload: RequestPayload::Ping,
+        };
+        round_trip(&message);
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({ "type": "request", "seq_id": 23, "payload": { "action": "ping" } })
+        );
+        assert!(!RequestPayload::Ping.can_batch());
+    }
+
+    #[test]
+    fn subscription_round_trip() {
+        round_trip(&AcpMessage::Request {
+            seq_id: 12,
+            timeout_ms: None,
+            payload: RequestPayload::Subscribe {
+                events: vec!["state_changed".to_string(), "focus_changed".to_string()],
+            },
+        });
+        round_trip(&AcpMessage::Request {
+            seq_id: 13,
+            timeout_ms: None,
+            payload: RequestPayload::Unsubscribe { subscription_id: 12 },
+        });
+        for payload in [
+            EventPayload::ElementAdded {
+                parent_id: Some("root".to_string()),
+                element: UiElement::new("save_button", "button").with_text("Save"),
+            },
+            EventPayload::FocusChanged {
+                element_id: Some("save_button".to_string()),
+            },
+            EventPayload::WindowClosed,
+        ] {
+            round_trip(&AcpMessage::Event { seq_id: 12, payload });
+        }
+
+        let subscribe_all: RequestPayload =
+            serde_json::from_value(json!({ "action": "subscribe" })).unwrap();
+        assert_eq!(subscribe_all, RequestPayload::Subscribe { events: vec![] });
+        assert_eq!(
+            serde_json::to_value(EventPayload::WindowClosed).unwrap(),
+            json!({ "event": "window_closed" })
+        );
+    }
+
+    #[test]
+    fn request_wire_format() {
+        let message = AcpMessage::Request {
+            seq_id: 1,
+            timeout_ms: None,
+            payload: RequestPayload::CustomCommand {
+                command_name: "CYCLE_COLOR".to_string(),
+                params: None,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            json!({
+                "type": "request",
+                "seq_id": 1,
+                "payload": { "action": "custom_command", "command_name": "CYCLE_COLOR" }
+            })
+        );
+    }
+
+    #[test]
+    fn error_response_wire_format() {
+        let payload = ResponsePayload::failure(
+            AcpError::new(ErrorCode::InvalidParams, "未知事件: nope")
+                .with_details(json!({ "path": "payload.events[0]" })),
+        );
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "success": false,
+                "message": "错误: 未知事件: nope",
+                "error": {
+                    "code": "invalid_params",
+                    "message": "未知事件: nope",
+                    "details": { "path": "payload.events[0]" }
+                }
+            })
+        );
+
+        // 旧版本服务端的失败响应没有错误对象
+        let legacy: ResponsePayload =
+            serde_json::from_value(json!({ "success": false, "message": "错误: 未知命令" })).unwrap();
+        assert_eq!(legacy.error, None);
+    }
+
+    #[test]
+    fn parses_legacy_request_with_null_fields() {
+        // 旧版客户端会把未使用的字段序列化为 null
+        let line = r#"{"type":"request","seq_id":3,"payload":{"action":"custom_command","command_name":"CYCLE_COLOR","element_id":null,"target_query":null,"params":null}}"#;
+        assert_eq!(
+            AcpMessage::from_line(line).unwrap(),
+            AcpMessage::Request {
+                seq_id: 3,
+                timeout_ms: None,
+                payload: RequestPayload::CustomCommand {
+                    command_name: "CYCLE_COLOR".to_string(),
+                    params: None,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_message_type() {
+        let line = r#"{"type":"notify","seq_id":1,"payload":{}}"#;
+        assert!(AcpMessage::from_line(line).is_err());
+    }
+
+    #[test]
+    fn parse_line_reports_invalid_json() {
+        let invalid = AcpMessage::parse_line("{\"type\":\"request\",").unwrap_err();
+        assert_eq!(invalid.seq_id, None);
+        assert_eq!(invalid.error.code, ErrorCode::ParseError);
+        assert_eq!(invalid.error.details, Some(json!({ "line": 1, "column": 18 })));
+    }
+
+    #[test]
+    fn parse_line_recovers_seq_id_and_path() {
+        let cases = [
+            (r#"{"type":"notify","seq_id":1,"payload":{}}"#, Some(1), "type"),
+            (r#"{"type":"request","seq_id":-1,"payload":{}}"#, None, "seq_id"),
+            (r#"{"type":"request","seq_id":2}"#, Some(2), "payload"),
+            (r#"{"type":"request","seq_id":3,"payload":{}}"#, Some(3), "payload.action"),
+            (
+                r#"{"type":"request","seq_id":4,"payload":{"action":"explode"}}"#,
+                Some(4),
+                "payload.action",
+            ),
+            (
+                r#"{"type":"request","seq_id":5,"payload":{"action":"custom_command"}}"#,
+                Some(5),
+                "payload.command_name",
+            ),
+            (
+                r#"{"type":"request","seq_id":6,"payload":{"action":"scroll","element_id":"list","dy":"far"}}"#,
+                Some(6),
+                "payload.dy",
+            ),
+            (
+                r#"{"type":"request","seq_id":7,"timeout_ms":"soon","payload":{"action":"ping"}}"#,
+                Some(7),
+                "timeout_ms",
+            ),
+            (
+                r#"{"type":"request","seq_id":8,"payload":{"action":"hello","protocol_version":1,"client_version":"0.1"}}"#,
+                Some(8),
+                "payload.client_name",
+            ),
+            (
+                r#"{"type":"request","seq_id":9,"payload":{"action":"batch","requests":[{"action":"ping"},{"action":"custom_command"}]}}"#,
+                Some(9),
+                "payload.requests[1].command_name",
+            ),
+            (
+                r#"{"type":"request","seq_id":10,"payload":{"action":"batch","requests":[{"action":"jump"}]}}"#,
+                Some(10),
+                "payload.requests[0].action",
+            ),
+        ];
+        for (line, seq_id, path) in cases {
+            let invalid = AcpMessage::parse_line(line).unwrap_err();
+            assert_eq!(invalid.seq_id, seq_id, "{}", line);
+            assert_eq!(invalid.error.code, ErrorCode::InvalidRequest, "{}", line);
+            assert_eq!(invalid.error.details, Some(json!({ "path": path })), "{}", line);
+        }
+
+        let invalid = AcpMessage::parse_line("[1, 2]").unwrap_err();
+        assert_eq!(invalid.error.code, ErrorCode::InvalidRequest);
+        assert_eq!(invalid.error.details, None);
+    }
+
+    #[test]
+    fn required_fields_match_the_payload_definitions() {
+        let target = ElementTarget::id("button");
+        let requests = [
+            RequestPayload::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_name: "agentkit_layer".to_string(),
+                client_version: "0.1.0".to_string(),
+                token: Some("3f9a".to_string()),
+            },
+            RequestPayload::CustomCommand {
+                command_name: "CYCLE_COLOR".to_string(),
+                params: Some(json!({})),
+            },
+            RequestPayload::ListCommands,
+            RequestPayload::GetUiTree,
+            RequestPayload::Click { target: target.clone() },
+            RequestPayload::Focus { target: target.clone() },
+            RequestPayload::SetValue {
+                target: target.clone(),
+                value: json!("text"),
+            },
+            RequestPayload::Toggle { target: target.clone() },
+            RequestPayload::Scroll {
+                target,
+                dx: 1.0,
+                dy: 2.0,
+            },
+            RequestPayload::GetAccessibilityTree,
+            RequestPayload::AccessibilityAction {
+                request: accesskit::ActionRequest {
+                    action: accesskit::Action::Click,
+                    target: accesskit::NodeId(1),
+                    data: None,
+                },
+            },
+            RequestPayload::Subscribe {
+                events: vec!["focus_changed".to_string()],
+            },
+            RequestPayload::Unsubscribe { subscription_id: 1 },
+            RequestPayload::Cancel { target_seq_id: 1 },
+            RequestPayload::Ping,
+            RequestPayload::Batch {
+                requests: vec![RequestPayload::Ping],
+                atomic: true,
+            },
+        ];
+
+        // 缺少必需字段时无法解析，缺少其他字段时仍能解析
+        for request in requests {
+            let action = request.action_name();
+            let required = RequestPayload::required_fields(action).unwrap();
+            let fields = serde_json::to_value(&request).unwrap().as_object().unwrap().clone();
+            for field in fields.keys().filter(|field| *field != "action") {
+                let mut without = fields.clone();
+                without.remove(field);
+                let parsed = serde_json::from_value::<RequestPayload>(Value::Object(without));
+                let is_required = required.contains(&field.as_str());
+                assert_eq!(parsed.is_err(), is_required, "{}.{}", action, field);
+            }
+            for field in required {
+                assert!(fields.contains_key(*field), "{}.{}", action, field);
+            }
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! `target_query` 元素查询语言
+//!
+//! 查询由空格分隔的若干条件组成，元素必须满足全部条件：
+//!
+//! - `key=value`：字段与值完全相同
+//! - `key~value`：字段包含该值，不区分大小写
+//!
+//! 可用的字段有 `id`、`role`、`label`、`text`、`name`（匹配 `label` 或 `text`）
+//! 以及 `path`（从根元素开始的子元素下标，例如 `path=0/1`，只支持 `=`）。
+//! 值中包含空格时用双引号括起来，例如 `text="Save as"`。
+
+use crate::UiElement;
+use std::fmt;
+
+/// 查询条件的比较方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equals,
+    Contains,
+}
+
+/// 单个查询条件
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Field { key: Field, op: Op, value: String },
+    Path(Vec<usize>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Id,
+    Role,
+    Label,
+    Text,
+    Name,
+}
+
+/// 解析后的元素查询
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementQuery {
+    terms: Vec<Term>,
+}
+
+/// 查询出错时的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// 查询语法错误
+    Parse(String),
+    /// 没有元素满足查询
+    NotFound(String),
+    /// 有多个元素满足查询
+    Ambiguous {
+        query: String,
+        candidates: Vec<UiElement>,
+    },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Parse(message) => write!(f, "查询语法错误: {}", message),
+            QueryError::NotFound(query) => write!(f, "没有元素满足查询: {}", query),
+            QueryError::Ambiguous { query, candidates } => {
+                write!(f, "有 {} 个元素满足查询 {}: ", candidates.len(), query)?;
+                let summaries: Vec<String> = candidates.iter().map(describe).collect();
+                write!(f, "{}", summaries.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// 生成元素的简短描述，用于列出候选元素
+fn describe(element: &UiElement) -> String {
+    match element.label.as_ref().or(element.text.as_ref()) {
+        Some(name) => format!("{} ({} \"{}\")", element.id, element.role, name),
+        None => format!("{} ({})", element.id, element.role),
+    }
+}
+
+impl ElementQuery {
+    /// 解析查询字符串
+    pub fn parse(query: &str) -> Result<Self, QueryError> {
+        let mut terms = Vec::new();
+        for token in tokenize(query)? {
+            terms.push(parse_term(&token)?);
+        }
+        if terms.is_empty() {
+            return Err(QueryError::Parse("查询为空".to_string()));
+        }
+        Ok(Self { terms })
+    }
+
+    /// 在元素树中查找满足查询的全部元素，按深度优先顺序返回
+    pub fn find_all<'a>(&self, root: &'a UiElement) -> Vec<&'a UiElement> {
+        let mut matches = Vec::new();
+        let mut path = Vec::new();
+        self.collect(root, &mut path, &mut matches);
+        matches
+    }
+
+    fn collect<'a>(
+        &self,
+        element: &'a UiElement,
+        path: &mut Vec<usize>,
+        matches: &mut Vec<&'a UiElement>,
+    ) {
+        if self.matches(element, path) {
+            matches.push(element);
+        }
+        for (index, child) in element.children.iter().enumerate() {
+            path.push(index);
+            self.collect(child, path, matches);
+            path.pop();
+        }
+    }
+
+    fn matches(&self, element: &UiElement, path: &[usize]) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Path(expected) => expected == path,
+            Term::Field { key, op, value } => {
+                let candidates: Vec<&str> = match key {
+                    Field::Id => vec![element.id.as_str()],
+                    Field::Role => vec![element.role.as_str()],
+                    Field::Label => element.label.as_deref().into_iter().collect(),
+                    Field::Text => element.text.as_deref().into_iter().collect(),
+                    Field::Name => element
+                        .label
+                        .as_deref()
+                        .into_iter()
+                        .chain(element.text.as_deref())
+                        .collect(),
+                };
+                candidates.iter().any(|candidate| match op {
+                    Op::Equals => *candidate == value,
+                    Op::Contains => candidate.to_lowercase().contains(&value.to_lowercase()),
+                })
+            }
+        })
+    }
+}
+
+/// 在元素树中解析查询，要求恰好有一个元素满足
+pub fn resolve<'a>(root: &'a UiElement, query: &str) -> Result<&'a UiElement, QueryError> {
+    let matches = ElementQuery::parse(query)?.find_all(root);
+    match matches.len() {
+        0 => Err(QueryError::NotFound(query.to_string())),
+        1 => Ok(matches[0]),
+        _ => Err(QueryError::Ambiguous {
+            query: query.to_string(),
+            candidates: matches.into_iter().cloned().map(without_children).collect(),
+        }),
+    }
+}
+
+/// 候选列表只需要元素本身的信息
+fn without_children(mut element: UiElement) -> UiElement {
+    element.children.clear();
+    element
+}
+
+/// 按空格切分查询，双引号内的空格保留
+fn tokenize(query: &str) -> Result<Vec<String>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = query.chars();
+    let mut in_quotes = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            '\\' if in_quotes => match chars.next() {
+                Some(escaped) => {
+                    current.push('\\');
+                    current.push(escaped);
+                }
+                None => return Err(QueryError::Parse("转义符后缺少字符".to_string())),
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err(QueryError::Parse("引号未闭合".to_string()));
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+/// 解析单个 `key=value` 或 `key~value` 条件
+fn parse_term(token: &str) -> Result<Term, QueryError> {
+    let op_index = token
+        .find(['=', '~'])
+        .ok_or_else(|| QueryError::Parse(format!("条件缺少 '=' 或 '~': {}", token)))?;
+    let key = &token[..op_index];
+    let op = if token[op_index..].starts_with('=') {
+        Op::Equals
+    } else {
+        Op::Contains
+    };
+    let value = unquote(&token[op_index + 1..])?;
+
+    let field = match key {
+        "id" => Field::Id,
+        "role" => Field::Role,
+        "label" => Field::Label,
+        "text" => Field::Text,
+        "name" => Field::Name,
+        "path" => {
+            if op != Op::Equals {
+                return Err(QueryError::Parse("path 只支持 '='".to_string()));
+            }
+            return parse_path(&value).map(Term::Path);
+        }
+        _ => return Err(QueryError::Parse(format!("未知字段: {}", key))),
+    };
+
+    Ok(Term::Field {
+        key: field,
+        op,
+        value,
+    })
+}
+
+/// 去掉值两侧的引号并处理转义
+fn unquote(value: &str) -> Result<String, QueryError> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Ok(value.to_string());
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| QueryError::Parse(format!("引号未闭合: {}", value)))?;
+
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                result.push(escaped);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Ok(result)
+}
+
+/// 解析形如 `0/1/2` 的下标路径，空字符串表示根元素
+fn parse_path(value: &str) -> Result<Vec<usize>, QueryError> {
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+    value
+        .split('/')
+        .map(|index| {
+            index
+                .parse()
+                .map_err(|_| QueryError::Parse(format!("无效的路径下标: {}", index)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_tree() -> UiElement {
+        UiElement::new("root", "window")
+            .with_label("AgentKit 目标应用")
+            .with_children(vec![
+                UiElement::new("color_label", "label")
+                    .with_label("当前背景")
+                    .with_text("当前背景: Light Blue"),
+                UiElement::new("cycle_color_button", "button")
+                    .with_label("循环背景颜色")
+                    .with_text("Change color"),
+                UiElement::new("save_button", "button").with_text("Save"),
+            ])
+    }
+
+    #[test]
+    fn resolves_by_role_and_name() {
+        let root = sample_tree();
+        let element = resolve(&root, r#"role=button name~"COLOR""#).unwrap();
+        assert_eq!(element.id, "cycle_color_button");
+    }
+
+    #[test]
+    fn resolves_exact_text_and_path() {
+        let root = sample_tree();
+        assert_eq!(resolve(&root, r#"text="Save""#).unwrap().id, "save_button");
+        assert_eq!(resolve(&root, "path=0").unwrap().id, "color_label");
+        assert_eq!(resolve(&root, "path=").unwrap().id, "root");
+    }
+
+    #[test]
+    fn reports_ambiguity_with_candidates() {
+        let root = sample_tree();
+        match resolve(&root, "role=button") {
+            Err(QueryError::Ambiguous { candidates, .. }) => {
+                let ids: Vec<&str> = candidates.iter().map(|c| c.id.as_str()).collect();
+                assert_eq!(ids, ["cycle_color_button", "save_button"]);
+            }
+            other => panic!("预期歧义错误，实际为 {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_not_found_and_parse_errors() {
+        let root = sample_tree();
+        assert_eq!(
+            resolve(&root, "role=checkbox"),
+            Err(QueryError::NotFound("role=checkbox".to_string()))
+        );
+        assert!(matches!(resolve(&root, "role"), Err(QueryError::Parse(_))));
+        assert!(matches!(resolve(&root, r#"text="Save"#), Err(QueryError::Parse(_))));
+        assert!(matches!(resolve(&root, "colour=blue"), Err(QueryError::Parse(_))));
+        assert!(matches!(resolve(&root, "path~0"), Err(QueryError::Parse(_))));
+        assert!(matches!(resolve(&root, "  "), Err(QueryError::Parse(_))));
+    }
+
+    #[test]
+    fn quoted_values_keep_spaces_and_escapes() {
+        let root = UiElement::new("root", "window").with_children(vec![
+            UiElement::new("a", "button").with_text(r#"Say "hi" now"#),
+        ]);
+        assert_eq!(resolve(&root, r#"text="Say \"hi\" now""#).unwrap().id, "a");
+    }
+}
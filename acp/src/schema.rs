@@ -0,0 +1,250 @@
+//! 命令参数的 JSON Schema 校验
+//!
+//! 只支持描述命令参数常用的一个子集，其他关键字（例如 `description`）被忽略：
+//!
+//! - `type`：`object`、`array`、`string`、`number`、`integer`、`boolean`、`null`，也可以是它们组成的数组
+//! - `enum`：值必须与其中一项相同
+//! - `minimum`、`maximum`：数值的范围，包含边界
+//! - `properties`、`required`、`additionalProperties`、`minProperties`、`maxProperties`：对象的字段
+//! - `items`：数组的每一项
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// 参数不满足 Schema 时的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    /// 出错的值相对于参数的路径，例如 `hsl.h` 或 `items[2]`，参数本身出错时为空
+    pub path: String,
+    pub message: String,
+}
+
+impl SchemaError {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// 以 `prefix` 为参数本身的完整路径，例如 `payload.params.hsl.h`
+    pub fn path_under(&self, prefix: &str) -> String {
+        join_path(prefix, &self.path)
+    }
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "参数{}", self.message)
+        } else {
+            write!(f, "参数 {} {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// 校验 `value` 是否满足 `schema`，返回遇到的第一个错误
+pub fn validate(schema: &Value, value: &Value) -> Result<(), SchemaError> {
+    check(schema, value, "")
+}
+
+fn join_path(parent: &str, child: &str) -> String {
+    if parent.is_empty() || child.is_empty() || child.starts_with('[') {
+        format!("{}{}", parent, child)
+    } else {
+        format!("{}.{}", parent, child)
+    }
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), SchemaError> {
+    // `true`、`{}` 等不是对象的 Schema 不做限制
+    let Value::Object(schema) = schema else {
+        return Ok(());
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            return Err(SchemaError::new(
+                path,
+                format!("应为 {} 类型，实际为 {}", types.join(" 或 "), type_name(value)),
+            ));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return Err(SchemaError::new(
+                path,
+                format!("必须是以下值之一: {}", allowed.join(", ")),
+            ));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                return Err(SchemaError::new(path, format!("不能小于 {}", minimum)));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                return Err(SchemaError::new(path, format!("不能大于 {}", maximum)));
+            }
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(schema, object, path),
+        Value::Array(items) => match schema.get("items") {
+            Some(item_schema) => items.iter().enumerate().try_for_each(|(index, item)| {
+                check(item_schema, item, &join_path(path, &format!("[{}]", index)))
+            }),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn check_object(schema: &Map<String, Value>, object: &Map<String, Value>, path: &str) -> Result<(), SchemaError> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        if let Some(missing) = required
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|name| !object.contains_key(*name))
+        {
+            return Err(SchemaError::new(&join_path(path, missing), "是必填字段"));
+        }
+    }
+
+    if let Some(minimum) = schema.get("minProperties").and_then(Value::as_u64) {
+        if (object.len() as u64) < minimum {
+            return Err(SchemaError::new(path, format!("至少需要 {} 个字段", minimum)));
+        }
+    }
+    if let Some(maximum) = schema.get("maxProperties").and_then(Value::as_u64) {
+        if object.len() as u64 > maximum {
+            return Err(SchemaError::new(path, format!("最多只能有 {} 个字段", maximum)));
+        }
+    }
+
+    let empty = Map::new();
+    let properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    for (name, field) in object {
+        let field_path = join_path(path, name);
+        match (properties.get(name), schema.get("additionalProperties")) {
+            (Some(field_schema), _) => check(field_schema, field, &field_path)?,
+            (None, Some(Value::Bool(false))) => {
+                let mut known: Vec<&str> = properties.keys().map(String::as_str).collect();
+                known.sort_unstable();
+                return Err(SchemaError::new(
+                    &field_path,
+                    format!("不是可用的字段，可用的字段: {}", known.join(", ")),
+                ));
+            }
+            (None, Some(extra_schema)) => check(extra_schema, field, &field_path)?,
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        // 不认识的类型不做限制
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn color_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "color": { "type": "string", "enum": ["white", "light_blue"] },
+                "hsl": {
+                    "type": "object",
+                    "properties": {
+                        "h": { "type": "number", "minimum": 0, "maximum": 360 },
+                        "s": { "type": "number", "minimum": 0, "maximum": 1 },
+                        "l": { "type": "number", "minimum": 0, "maximum": 1 }
+                    },
+                    "required": ["h", "s", "l"]
+                }
+            },
+            "additionalProperties": false,
+            "minProperties": 1,
+            "maxProperties": 1
+        })
+    }
+
+    #[test]
+    fn accepts_valid_params() {
+        let schema = color_schema();
+        assert_eq!(validate(&schema, &json!({ "color": "white" })), Ok(()));
+        assert_eq!(
+            validate(&schema, &json!({ "hsl": { "h": 30, "s": 0.5, "l": 0.8 } })),
+            Ok(())
+        );
+        // 不是对象的 Schema 不做限制
+        assert_eq!(validate(&json!({}), &json!([1, "a"])), Ok(()));
+    }
+
+    #[test]
+    fn reports_path_of_first_error() {
+        let schema = color_schema();
+        let error = validate(&schema, &json!({ "hsl": { "h": 400, "s": 0.5, "l": 0.8 } })).unwrap_err();
+        assert_eq!(error.path, "hsl.h");
+        assert_eq!(error.path_under("payload.params"), "payload.params.hsl.h");
+
+        let error = validate(&schema, &json!({ "hsl": { "h": 30, "s": 0.5 } })).unwrap_err();
+        assert_eq!(error.path, "hsl.l");
+
+        let error = validate(&schema, &json!({ "colour": "white" })).unwrap_err();
+        assert_eq!(error.path, "colour");
+
+        let error = validate(&schema, &json!({})).unwrap_err();
+        assert_eq!(error.path_under("payload.params"), "payload.params");
+    }
+
+    #[test]
+    fn checks_types_enums_and_items() {
+        let schema = json!({ "type": "array", "items": { "type": "integer" } });
+        assert_eq!(validate(&schema, &json!([1, 2.0])), Ok(()));
+        assert_eq!(validate(&schema, &json!([1, 2.5])).unwrap_err().path, "[1]");
+        assert!(validate(&color_schema(), &json!({ "color": "black" })).is_err());
+        assert!(validate(&json!({ "type": ["string", "null"] }), &json!(null)).is_ok());
+    }
+}
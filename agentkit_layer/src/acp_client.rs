@@ -0,0 +1,711 @@
+use acp::{
+    AcpError, AcpMessage, CommandInfo, ErrorCode, EventPayload, RequestPayload, ResponseData, ResponsePayload, UiElement, Welcome,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::transport::Connection;
+use rand::Rng;
+use std::{
+    collections::{HashSet, VecDeque},
+    error::Error,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tungstenite::{Error as WsError, Message, WebSocket};
+
+/// 不支持握手的旧版应用只能处理的 `action`
+const LEGACY_ACTIONS: &[&str] = &["custom_command"];
+
+/// 建立连接的超时
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 发送请求的超时
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 默认的响应超时
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 请求带有 `timeout_ms` 时，超过该时间后再多等待的时间，以便收到应用的 `timed_out` 回复
+const RESPONSE_GRACE: Duration = Duration::from_secs(2);
+
+/// WebSocket 读取的轮询间隔，每次轮询后发送排队的请求
+const WS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 订阅收到的应用事件
+#[derive(Debug, Clone)]
+pub struct AcpEvent {
+    /// 订阅请求的 `seq_id`
+    pub subscription_id: u64,
+    pub payload: EventPayload,
+}
+
+/// 读线程和客户端共享的订阅状态
+#[derive(Default)]
+struct Subscriptions {
+    /// 有效的订阅 ID
+    ids: HashSet<u64>,
+    /// 尚未取走的事件
+    pending: VecDeque<AcpEvent>,
+}
+
+/// 读线程转交的响应，附带响应的 `seq_id`
+type Responses = Receiver<(u64, ResponsePayload)>;
+
+/// 请求的发送端
+enum Writer {
+    /// 字节流，每行一条消息
+    Stream(Connection),
+    /// 交给 WebSocket 线程发送，每个文本帧一条消息
+    WebSocket(Sender<String>),
+}
+
+impl Writer {
+    fn send_line(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        match self {
+            Writer::Stream(stream) => stream.write_all(line.as_bytes())?,
+            Writer::WebSocket(sender) => sender
+                .send(line.trim_end().to_string())
+                .map_err(|_| "WebSocket 连接已关闭")?,
+        }
+        Ok(())
+    }
+
+    /// 关闭连接，读线程随之退出。WebSocket 在发送端被丢弃时关闭
+    fn shutdown(&self) {
+        if let Writer::Stream(stream) = self {
+            let _ = stream.shutdown();
+        }
+    }
+}
+
+/// ACP 客户端，在同一条连接上发送多个请求。
+/// 后台读线程按 `seq_id` 区分响应和订阅的事件
+pub struct AcpClient {
+    /// 目标应用的地址，兼容模式下每个请求重新连接
+    addr: String,
+    writer: Writer,
+    responses: Responses,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    /// 单个请求的超时
+    response_timeout: Duration,
+    /// 握手得到的应用信息，旧版应用为 `None`
+    welcome: Option<Welcome>,
+    /// 握手时出示的认证令牌
+    token: Option<String>,
+    /// 读线程退出后置位，说明连接已断开
+    closed: Arc<AtomicBool>,
+    /// 旧版应用每条连接只处理一个请求，回复后关闭连接
+    legacy: bool,
+    /// 当前连接是否已经发送过请求，兼容模式下发送过请求的连接不能再用
+    used: bool,
+}
+
+impl AcpClient {
+    /// 连接到目标应用并完成握手，
+    /// `addr` 为 `主机:端口`、`unix:<路径>` 或 `ws://主机:端口/路径`，
+    /// `token` 为应用要求认证时在握手中出示的令牌
+    pub fn connect(addr: &str, token: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let (writer, responses, closed) = open_connection(addr, &subscriptions)?;
+
+        let mut client = Self {
+            addr: addr.to_string(),
+            writer,
+            responses,
+            subscriptions,
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+            welcome: None,
+            token: token.map(str::to_string),
+            closed,
+            legacy: false,
+            used: false,
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// 关闭当前连接，重新连接到同一地址
+    fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.shutdown();
+        let (writer, responses, closed) = open_connection(&self.addr, &self.subscriptions)?;
+        self.writer = writer;
+        self.responses = responses;
+        self.closed = closed;
+        self.used = false;
+        Ok(())
+    }
+
+    /// 设置单个请求的超时。应用支持取消时，该时间作为请求的 `timeout_ms` 交给应用执行，
+    /// 客户端再多等待一段时间接收应用的 `timed_out` 回复
+    pub fn set_response_timeout(&mut self, timeout: Duration) {
+        self.response_timeout = timeout;
+    }
+
+    /// 握手得到的应用信息，旧版应用为 `None`
+    pub fn welcome(&self) -> Option<&Welcome> {
+        self.welcome.as_ref()
+    }
+
+    /// 连接是否已被对端关闭。旧版应用回复后总会关闭连接，兼容模式下始终为 `false`
+    pub fn is_closed(&self) -> bool {
+        !self.legacy && self.closed.load(Ordering::SeqCst)
+    }
+
+    /// 发送心跳并在 `timeout` 内等待回复。
+    /// 不支持心跳的应用只检查连接是否已关闭，兼容模式下的连接问题在下一个请求时发现
+    pub fn ping(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        if self.is_closed() {
+            return Err("目标应用已关闭 ACP 连接".into());
+        }
+        if !self.welcome.as_ref().is_some_and(|w| w.supports_action("ping")) {
+            return Ok(());
+        }
+        let response = self.send_request_with_seq(next_seq_id(), RequestPayload::Ping, timeout)?;
+        if !response.success {
+            return Err(format!("心跳失败: {}", response.message).into());
+        }
+        Ok(())
+    }
+
+    /// 发送 `hello` 并检查应用的协议版本和能力
+    fn handshake(&mut self) -> Result<(), Box<dyn Error>> {
+        let response = self.send_request(RequestPayload::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: env!("CARGO_PKG_NAME").to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            token: self.token.clone(),
+        })?;
+
+        let welcome = match response.data {
+            Some(ResponseData::Welcome(welcome)) => welcome,
+            _ => {
+                // 带有错误对象的应用认识 hello，失败说明握手被拒绝，例如令牌不正确
+                if let Some(error) = response.error {
+                    return Err(Box::new(error));
+                }
+                // 旧版应用不认识 hello，回复后就关闭了连接。
+                // 重新连接一次确认应用可用，之后降级为只使用 custom_command，每个请求使用新的连接
+                self.legacy = true;
+                self.reconnect()
+                    .map_err(|e| format!("目标应用不支持 ACP 握手，兼容模式下重新连接失败: {}", e))?;
+                println!("目标应用不支持 ACP 握手，将以兼容模式运行");
+                return Ok(());
+            }
+        };
+
+        if !response.success || welcome.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "ACP 协议版本不兼容: 客户端支持 {}-{}，应用协商为 {} ({})",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, welcome.protocol_version, response.message
+            )
+            .into());
+        }
+
+        println!(
+            "已与 {} {} 完成握手 (协议版本 {})，支持的命令: {}",
+            welcome.app_name,
+            welcome.app_version,
+            welcome.protocol_version,
+            welcome.commands.join(", ")
+        );
+        self.welcome = Some(welcome);
+        Ok(())
+    }
+
+    /// 发送请求并等待对应的响应。
+    /// 应用不支持该 `action` 或命令时不发送请求，直接返回 `AcpError`，批处理中的每个请求都会检查。
+    /// 等待响应超时时返回 `timed_out` 的 `AcpError`，连接仍然可用
+    pub fn request(&mut self, payload: RequestPayload) -> Result<ResponsePayload, Box<dyn Error>> {
+        self.check_supported(&payload)?;
+        if let RequestPayload::Batch { requests, .. } = &payload {
+            for request in requests {
+                self.check_supported(request)?;
+            }
+        }
+        self.send_request(payload)
+    }
+
+    /// 检查应用是否支持该请求的 `action` 和命令
+    fn check_supported(&self, payload: &RequestPayload) -> Result<(), AcpError> {
+        let action = payload.action_name();
+        let supported = match &self.welcome {
+            Some(welcome) => welcome.supports_action(action),
+            None => LEGACY_ACTIONS.contains(&action),
+        };
+        if !supported {
+            return Err(AcpError::new(
+                ErrorCode::InvalidRequest,
+                format!("目标应用不支持 action: {}", action),
+            ));
+        }
+
+        if let (Some(welcome), RequestPayload::CustomCommand { command_name, .. }) =
+            (&self.welcome, payload)
+        {
+            if !welcome.supports_command(command_name) {
+                return Err(AcpError::new(
+                    ErrorCode::UnknownCommand,
+                    format!("目标应用不支持命令: {}", command_name),
+                )
+                .with_details(serde_json::json!({ "available_commands": welcome.commands })));
+            }
+        }
+        Ok(())
+    }
+
+    /// 发送请求并读取响应，不做能力检查
+    fn send_request(&mut self, payload: RequestPayload) -> Result<ResponsePayload, Box<dyn Error>> {
+        self.send_request_with_seq(next_seq_id(), payload, self.response_timeout)
+    }
+
+    /// 发送请求并在 `timeout` 内等待响应
+    fn send_request_with_seq(
+        &mut self,
+        seq_id: u64,
+        payload: RequestPayload,
+        timeout: Duration,
+    ) -> Result<ResponsePayload, Box<dyn Error>> {
+        // 旧版应用不认识 timeout_ms，只交给支持取消的应用。
+        // 应用在 timeout_ms 到期时自己回复 timed_out，客户端多等待一段时间，避免在回复途中放弃
+        let timeout_ms = self.supports_cancel().then_some(timeout.as_millis() as u64);
+        let wait = if timeout_ms.is_some() {
+            timeout + RESPONSE_GRACE
+        } else {
+            timeout
+        };
+        // 心跳定期发送，不打印
+        let quiet = payload == RequestPayload::Ping;
+
+        // 将 ACP 消息转换为带换行符的 JSON 字符串
+        let message_with_newline = AcpMessage::Request {
+            seq_id,
+            timeout_ms,
+            payload,
+        }
+        .to_line()
+        .map_err(|e| format!("序列化 ACP 消息失败: {}", e))?;
+
+        if !quiet {
+            println!("发送 ACP 请求: {}", message_with_newline);
+        }
+
+        // 旧版应用回复后关闭连接，上一个请求用过的连接需要换掉
+        if self.legacy && self.used {
+            self.reconnect()
+                .map_err(|e| format!("连接目标应用失败: {}", e))?;
+        }
+        self.used = true;
+
+        // 发送 ACP 请求
+        self.writer
+            .send_line(&message_with_newline)
+            .map_err(|e| format!("发送 ACP 请求失败: {}", e))?;
+
+        // 等待读线程转交的响应，跳过之前超时的请求遗留的响应。
+        // 应用无法找回 seq_id 时以 0 回复，同一时间只有一个请求在等待，视为对它的响应
+        loop {
+            let (response_seq_id, payload) = match self.responses.recv_timeout(wait) {
+                Ok(response) => response,
+                Err(RecvTimeoutError::Timeout) => {
+                    // 连接仍然可用，超时以 AcpError 返回，调用方把它当作失败的请求而不是断线
+                    self.cancel(seq_id);
+                    return Err(Box::new(AcpError::new(
+                        ErrorCode::TimedOut,
+                        format!("等待 ACP 响应超时 ({} 秒)", wait.as_secs_f32()),
+                    )));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err("目标应用已关闭 ACP 连接".into()),
+            };
+            if response_seq_id == seq_id || response_seq_id == 0 {
+                if !quiet {
+                    println!(
+                        "收到 ACP 响应: {}",
+                        serde_json::to_string(&payload).unwrap_or_default()
+                    );
+                }
+                return Ok(payload);
+            }
+            eprintln!("忽略序列 ID 不匹配的 ACP 响应: 预期 {}, 实际 {}", seq_id, response_seq_id);
+        }
+    }
+
+    /// 应用是否支持 `cancel` 和 `timeout_ms`
+    fn supports_cancel(&self) -> bool {
+        self.welcome.as_ref().is_some_and(|w| w.supports_action("cancel"))
+    }
+
+    /// 通知应用放弃仍在执行的请求，不等待响应。
+    /// 被取消的请求和 `cancel` 本身的响应随后到达，会作为过期的响应被跳过
+    fn cancel(&mut self, seq_id: u64) {
+        if !self.supports_cancel() {
+            return;
+        }
+        let message = AcpMessage::Request {
+            seq_id: next_seq_id(),
+            timeout_ms: None,
+            payload: RequestPayload::Cancel {
+                target_seq_id: seq_id,
+            },
+        };
+        match message.to_line() {
+            Ok(line) => {
+                if let Err(e) = self.writer.send_line(&line) {
+                    eprintln!("发送 ACP 取消请求失败: {}", e);
+                }
+            }
+            Err(e) => eprintln!("序列化 ACP 消息失败: {}", e),
+        }
+    }
+
+    /// 订阅应用事件，`events` 为空表示全部事件，返回订阅 ID
+    pub fn subscribe(&mut self, events: Vec<String>) -> Result<u64, Box<dyn Error>> {
+        let payload = RequestPayload::Subscribe { events };
+        let action = payload.action_name();
+        if !self.welcome.as_ref().is_some_and(|w| w.supports_action(action)) {
+            return Err(format!("目标应用不支持 action: {}", action).into());
+        }
+
+        // 事件可能紧跟在响应之前到达，先登记订阅 ID
+        let seq_id = next_seq_id();
+        self.subscriptions.lock().unwrap().ids.insert(seq_id);
+
+        let result = self.send_request_with_seq(seq_id, payload, self.response_timeout);
+        match result {
+            Ok(response) if response.success => Ok(seq_id),
+            Ok(response) => {
+                self.forget_subscription(seq_id);
+                Err(format!("订阅事件失败: {}", response.message).into())
+            }
+            Err(e) => {
+                self.forget_subscription(seq_id);
+                Err(e)
+            }
+        }
+    }
+
+    /// 取消订阅
+    pub fn unsubscribe(&mut self, subscription_id: u64) -> Result<(), Box<dyn Error>> {
+        self.forget_subscription(subscription_id);
+        let response = self.request(RequestPayload::Unsubscribe { subscription_id })?;
+        if !response.success {
+            return Err(format!("取消订阅失败: {}", response.message).into());
+        }
+        Ok(())
+    }
+
+    fn forget_subscription(&self, subscription_id: u64) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.ids.remove(&subscription_id);
+        subscriptions
+            .pending
+            .retain(|event| event.subscription_id != subscription_id);
+    }
+
+    /// 取走目前收到的全部事件
+    pub fn take_events(&self) -> Vec<AcpEvent> {
+        self.subscriptions.lock().unwrap().pending.drain(..).collect()
+    }
+
+    /// 获取应用暴露的全部命令
+    pub fn list_commands(&mut self) -> Result<Vec<CommandInfo>, Box<dyn Error>> {
+        let response = self.request(RequestPayload::ListCommands)?;
+        match response.data {
+            Some(ResponseData::Commands { commands }) if response.success => Ok(commands),
+            _ => Err(format!("获取命令列表失败: {}", response.message).into()),
+        }
+    }
+
+    /// 获取应用当前的界面元素树
+    pub fn get_ui_tree(&mut self) -> Result<UiElement, Box<dyn Error>> {
+        let response = self.request(RequestPayload::GetUiTree)?;
+        match response.data {
+            Some(ResponseData::UiTree { root }) if response.success => Ok(root),
+            _ => Err(format!("获取界面元素树失败: {}", response.message).into()),
+        }
+    }
+}
+
+/// 连接到 `addr` 并启动读线程，返回请求的发送端、响应的接收端和连接关闭标志
+fn open_connection(
+    addr: &str,
+    subscriptions: &Arc<Mutex<Subscriptions>>,
+) -> Result<(Writer, Responses, Arc<AtomicBool>), Box<dyn Error>> {
+    let (response_sender, responses) = mpsc::channel();
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let writer = if addr.starts_with("ws://") {
+        let socket = open_websocket(addr)?;
+        let (sender, outgoing) = mpsc::channel();
+        let subscriptions = subscriptions.clone();
+        let closed = closed.clone();
+        thread::spawn(move || {
+            websocket_loop(socket, outgoing, response_sender, subscriptions);
+            closed.store(true, Ordering::SeqCst);
+        });
+        Writer::WebSocket(sender)
+    } else if addr.starts_with("wss://") {
+        return Err("暂不支持 wss://，请使用 ws:// 或 Unix 套接字".into());
+    } else {
+        let stream = Connection::open(addr, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let subscriptions = subscriptions.clone();
+        let closed = closed.clone();
+        thread::spawn(move || {
+            read_loop(reader, response_sender, subscriptions);
+            closed.store(true, Ordering::SeqCst);
+        });
+        Writer::Stream(stream)
+    };
+    Ok((writer, responses, closed))
+}
+
+/// 生成随机序列 ID，0 保留给应用无法找回 seq_id 的错误响应
+fn next_seq_id() -> u64 {
+    rand::thread_rng().gen_range(1..=u64::MAX)
+}
+
+impl Drop for AcpClient {
+    fn drop(&mut self) {
+        // 关闭连接，读线程随之退出
+        self.writer.shutdown();
+    }
+}
+
+/// 读线程，把响应交给等待中的请求，把事件放入订阅队列
+fn read_loop(
+    mut reader: BufReader<Connection>,
+    responses: Sender<(u64, ResponsePayload)>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("读取 ACP 消息失败: {}", e);
+                break;
+            }
+        }
+        if !dispatch_incoming(&line, &responses, &subscriptions) {
+            break;
+        }
+    }
+}
+
+/// 连接 `ws://主机:端口/路径` 并完成 WebSocket 握手
+fn open_websocket(url: &str) -> Result<WebSocket<Connection>, Box<dyn Error>> {
+    let rest = &url["ws://".len()..];
+    let host = rest.split('/').next().unwrap_or(rest);
+    // 省略端口时使用 WebSocket 的默认端口
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let stream = Connection::open(&host, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let (socket, _) = tungstenite::client(url, stream)
+        .map_err(|e| format!("WebSocket 握手失败: {}", e))?;
+    // 握手完成后改为轮询读取，以便在同一线程中发送请求
+    socket.get_ref().set_read_timeout(Some(WS_POLL_INTERVAL))?;
+    Ok(socket)
+}
+
+/// WebSocket 线程。WebSocket 无法在两个线程间拆分读写，
+/// 因此交替读取消息和发送排队的请求，发送端被丢弃时关闭连接
+fn websocket_loop(
+    mut socket: WebSocket<Connection>,
+    outgoing: Receiver<String>,
+    responses: Sender<(u64, ResponsePayload)>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) {
+    'connection: loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if !dispatch_incoming(&text, &responses, &subscriptions) {
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) => break,
+            Ok(_) => {}
+            Err(WsError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => break,
+            Err(e) => {
+                eprintln!("读取 ACP 消息失败: {}", e);
+                break;
+            }
+        }
+
+        loop {
+            match outgoing.try_recv() {
+                Ok(text) => {
+                    if let Err(e) = socket.send(Message::Text(text)) {
+                        eprintln!("发送 ACP 请求失败: {}", e);
+                        break 'connection;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    break 'connection;
+                }
+            }
+        }
+    }
+    let _ = socket.get_ref().shutdown();
+}
+
+/// 处理收到的一条消息，返回 `false` 表示客户端已被丢弃，读线程应退出
+fn dispatch_incoming(
+    line: &str,
+    responses: &Sender<(u64, ResponsePayload)>,
+    subscriptions: &Mutex<Subscriptions>,
+) -> bool {
+    if line.trim().is_empty() {
+        return true;
+    }
+
+    match AcpMessage::from_line(line) {
+        // 响应由等待它的请求打印
+        Ok(AcpMessage::Response { seq_id, payload }) => {
+            return responses.send((seq_id, payload)).is_ok();
+        }
+        Ok(AcpMessage::Event { seq_id, payload }) => {
+            let mut subscriptions = subscriptions.lock().unwrap();
+            if subscriptions.ids.contains(&seq_id) {
+                subscriptions.pending.push_back(AcpEvent {
+                    subscription_id: seq_id,
+                    payload,
+                });
+            } else {
+                eprintln!("忽略未订阅的 ACP 事件: {}", line.trim_end());
+            }
+        }
+        Ok(other) => eprintln!("收到意外的 ACP 消息: {:?}", other),
+        Err(e) => eprintln!("解析 ACP 消息失败: {}", e),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::net::TcpListener;
+
+    /// 模拟旧版应用：每条连接只读取一个请求，回复后关闭连接，不认识 hello
+    fn spawn_one_shot_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                if BufReader::new(&stream).read_line(&mut line).unwrap_or(0) == 0 {
+                    continue;
+                }
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let payload = match request["payload"]["action"].as_str() {
+                    Some("custom_command") => json!({ "success": true, "message": "已执行" }),
+                    _ => json!({ "success": false, "message": "错误: 不支持的 action 类型" }),
+                };
+                let response = json!({
+                    "type": "response",
+                    "seq_id": request["seq_id"],
+                    "payload": payload,
+                });
+                let _ = writeln!(stream, "{}", response);
+            }
+        });
+        addr
+    }
+
+    /// 模拟完成握手的应用：回复 hello 和 ping，从不回复 custom_command
+    fn spawn_unresponsive_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            for line in reader.lines() {
+                let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let payload = match request["payload"]["action"].as_str() {
+                    Some("hello") => json!({
+                        "success": true,
+                        "message": "ok",
+                        "data": {
+                            "kind": "welcome",
+                            "protocol_version": PROTOCOL_VERSION,
+                            "app_name": "slow_app",
+                            "app_version": "0.1.0",
+                            "actions": ["custom_command", "ping"],
+                            "commands": ["CYCLE_COLOR"],
+                        },
+                    }),
+                    Some("ping") => json!({ "success": true, "message": "pong" }),
+                    _ => continue,
+                };
+                let response = json!({
+                    "type": "response",
+                    "seq_id": request["seq_id"],
+                    "payload": payload,
+                });
+                writeln!(stream, "{}", response).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn cycle_color() -> RequestPayload {
+        RequestPayload::CustomCommand {
+            command_name: "CYCLE_COLOR".to_string(),
+            params: None,
+        }
+    }
+
+    #[test]
+    fn legacy_app_gets_a_fresh_connection_per_request() {
+        let addr = spawn_one_shot_server();
+        let mut client = AcpClient::connect(&addr, None).unwrap();
+        assert!(client.welcome().is_none());
+
+        for _ in 0..3 {
+            let response = client.request(cycle_color()).unwrap();
+            assert!(response.success, "{}", response.message);
+            assert!(!client.is_closed());
+        }
+        client.ping(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn legacy_app_rejects_unsupported_actions_locally() {
+        let addr = spawn_one_shot_server();
+        let mut client = AcpClient::connect(&addr, None).unwrap();
+
+        let error = client.request(RequestPayload::GetUiTree).unwrap_err();
+        assert!(error.downcast_ref::<AcpError>().is_some());
+    }
+
+    #[test]
+    fn response_timeout_keeps_the_connection() {
+        let addr = spawn_unresponsive_server();
+        let mut client = AcpClient::connect(&addr, None).unwrap();
+        client.set_response_timeout(Duration::from_millis(100));
+
+        let error = client.request(cycle_color()).unwrap_err();
+        let error = error.downcast_ref::<AcpError>().expect("超时应返回 AcpError");
+        assert_eq!(error.code, ErrorCode::TimedOut);
+        assert!(!client.is_closed());
+        client.ping(Duration::from_secs(1)).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use crate::acp_client::AcpClient;
+use std::{
+    error::Error,
+    fmt, panic,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, TryLockError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tokio::{sync::watch, task};
+
+/// 心跳间隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 等待心跳回复的最长时间
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 第一次重新连接前等待的时间，之后每次失败加倍
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// 重新连接的最长等待时间
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// 建立连接并完成握手和订阅的函数，应用重启后令牌会变化，每次重新连接都会调用
+type Connector = Box<dyn Fn() -> Result<AcpClient, Box<dyn Error>> + Send>;
+
+/// 与目标应用的连接状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// 连接已断开，后台线程正在重新连接，`attempt` 为已经尝试的次数
+    Reconnecting { attempt: u32 },
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionStatus::Connected => write!(f, "已连接"),
+            ConnectionStatus::Reconnecting { attempt: 0 } => write!(f, "连接已断开，正在重新连接"),
+            ConnectionStatus::Reconnecting { attempt } => {
+                write!(f, "连接已断开，正在重新连接 (已尝试 {} 次)", attempt)
+            }
+        }
+    }
+}
+
+/// 后台线程和前台共享的连接
+struct Shared {
+    client: Mutex<Option<AcpClient>>,
+    /// 每次重新连接成功后加一，前台据此判断是否需要重新获取命令列表
+    generation: Mutex<u64>,
+    status: watch::Sender<ConnectionStatus>,
+}
+
+/// 唤醒后台线程的原因
+enum Signal {
+    /// 前台发现连接已断开
+    Lost,
+    Shutdown,
+}
+
+/// 自动维护的 ACP 连接。后台线程定期发送心跳，
+/// 连接断开后按指数退避重新连接，状态变化通过 `watch_status` 通知前台
+pub struct ManagedConnection {
+    shared: Arc<Shared>,
+    signals: Sender<Signal>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ManagedConnection {
+    /// 接管已经建立的连接并启动后台线程
+    pub fn start(
+        client: AcpClient,
+        connector: impl Fn() -> Result<AcpClient, Box<dyn Error>> + Send + 'static,
+    ) -> Self {
+        let (status, _) = watch::channel(ConnectionStatus::Connected);
+        let shared = Arc::new(Shared {
+            client: Mutex::new(Some(client)),
+            generation: Mutex::new(0),
+            status,
+        });
+        let (signals, signal_receiver) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            let connector: Connector = Box::new(connector);
+            thread::spawn(move || supervise(shared, connector, signal_receiver))
+        };
+
+        Self {
+            shared,
+            signals,
+            worker: Some(worker),
+        }
+    }
+
+    /// 当前连接状态
+    pub fn status(&self) -> ConnectionStatus {
+        *self.shared.status.borrow()
+    }
+
+    /// 订阅连接状态的变化
+    pub fn watch_status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.shared.status.subscribe()
+    }
+
+    /// 连接成功的次数，首次连接为 0
+    pub fn generation(&self) -> u64 {
+        *self.shared.generation.lock().unwrap()
+    }
+
+    /// 在已连接的客户端上执行 `f`，连接已断开时返回 `None`。
+    /// 客户端的读写和锁都会阻塞（后台线程发送心跳时持有锁），因此在 tokio 的阻塞线程池中执行，
+    /// 不占用异步运行时的线程
+    pub async fn with_client<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut AcpClient) -> T + Send + 'static,
+    ) -> Option<T> {
+        let shared = self.shared.clone();
+        task::spawn_blocking(move || shared.client.lock().unwrap().as_mut().map(f))
+            .await
+            .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+    }
+
+    /// 前台请求失败、判断连接已断开时调用，立即开始重新连接
+    pub fn mark_lost(&self) {
+        if disconnect(&self.shared) {
+            let _ = self.signals.send(Signal::Lost);
+        }
+    }
+}
+
+impl Drop for ManagedConnection {
+    fn drop(&mut self) {
+        let _ = self.signals.send(Signal::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// 丢弃当前客户端并把状态改为重新连接中，已经断开时返回 `false`
+fn disconnect(shared: &Shared) -> bool {
+    if shared.client.lock().unwrap().take().is_none() {
+        return false;
+    }
+    shared
+        .status
+        .send_replace(ConnectionStatus::Reconnecting { attempt: 0 });
+    true
+}
+
+/// 后台线程：已连接时定期发送心跳，断开后按指数退避重新连接
+fn supervise(shared: Arc<Shared>, connector: Connector, signals: mpsc::Receiver<Signal>) {
+    let mut attempt = 0;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let connected = matches!(*shared.status.borrow(), ConnectionStatus::Connected);
+        let wait = if connected { HEARTBEAT_INTERVAL } else { backoff };
+        let lost = match signals.recv_timeout(wait) {
+            Ok(Signal::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+            Ok(Signal::Lost) => true,
+            Err(RecvTimeoutError::Timeout) if connected => !heartbeat(&shared) && disconnect(&shared),
+            Err(RecvTimeoutError::Timeout) => false,
+        };
+        if connected && !lost {
+            continue;
+        }
+
+        // 刚断开时立即重新连接，之后每次失败等待时间加倍
+        if lost {
+            attempt = 0;
+            backoff = INITIAL_BACKOFF;
+        }
+        if try_reconnect(&shared, &connector, &mut attempt) {
+            backoff = INITIAL_BACKOFF;
+        } else if !lost {
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// 发送一次心跳，返回连接是否仍然可用。前台正在使用连接时跳过本次心跳
+fn heartbeat(shared: &Shared) -> bool {
+    let mut client = match shared.client.try_lock() {
+        Ok(client) => client,
+        Err(TryLockError::WouldBlock) => return true,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+    };
+    match client.as_mut() {
+        Some(client) => match client.ping(HEARTBEAT_TIMEOUT) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("\nACP 心跳失败: {}", e);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+/// 尝试重新连接一次，成功时换上新的客户端
+fn try_reconnect(shared: &Shared, connector: &Connector, attempt: &mut u32) -> bool {
+    *attempt += 1;
+    match connector() {
+        Ok(client) => {
+            *shared.client.lock().unwrap() = Some(client);
+            *shared.generation.lock().unwrap() += 1;
+            shared.status.send_replace(ConnectionStatus::Connected);
+            *attempt = 0;
+            true
+        }
+        Err(e) => {
+            eprintln!("重新连接失败: {}", e);
+            shared
+                .status
+                .send_replace(ConnectionStatus::Reconnecting { attempt: *attempt });
+            false
+        }
+    }
+}
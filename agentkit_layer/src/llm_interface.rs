@@ -1,19 +1,76 @@
 use async_openai::{
     config::OpenAIConfig,
     types::{
-        ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
-        CreateChatCompletionRequestArgs, Role,
+        ChatCompletionMessageToolCall, ChatCompletionRequestSystemMessage, ChatCompletionRequestToolMessage,
+        ChatCompletionRequestUserMessage, ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, FunctionCall, FunctionObject, Role,
     },
     Client,
 };
 use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{error::Error, pin::Pin};
 
 /// 聊天消息结构体
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
     pub content: String,
+    /// 助手消息中模型请求的工具调用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// 工具消息回复的工具调用 ID，见 `ToolCall::id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    /// 系统消息
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new("system", content)
+    }
+
+    /// 用户消息
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
+    /// 工具消息，回复 ID 为 `tool_call_id` 的工具调用
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new("tool", content)
+        }
+    }
+
+    fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+/// 提供给模型调用的工具
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema，顶层必须是 object
+    pub parameters: Value,
+}
+
+/// 模型请求的一次工具调用
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// 模型生成的参数，是 JSON 字符串，不保证合法
+    pub arguments: String,
 }
 
 /// 聊天完成请求结构体
@@ -21,6 +78,9 @@ pub struct ChatMessage {
 pub struct ChatCompletionRequest {
     pub model: String,
     pub messages: Vec<ChatMessage>,
+    /// 模型可以调用的工具，为空时模型只回复文本
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
 }
 
 /// 聊天选择结构体
@@ -35,6 +95,120 @@ pub struct ChatCompletionResponse {
     pub choices: Vec<ChatChoice>,
 }
 
+/// 流式回复中的一段增量，只包含第一个选择
+#[derive(Debug, Clone, Default)]
+pub struct ChatCompletionDelta {
+    /// 新增的文本
+    pub content: String,
+    /// 新增的工具调用片段
+    pub tool_calls: Vec<ToolCallDelta>,
+}
+
+/// 工具调用的片段。同一个调用的片段 `index` 相同，`id` 和 `name` 通常只出现在第一个片段中
+#[derive(Debug, Clone, Default)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    /// 参数 JSON 字符串的一部分
+    pub arguments: String,
+}
+
+impl From<ChatMessage> for ChatCompletionDelta {
+    /// 完整的消息作为一段增量
+    fn from(message: ChatMessage) -> Self {
+        let tool_calls = message
+            .tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCallDelta {
+                index,
+                id: Some(call.id),
+                name: Some(call.name),
+                arguments: call.arguments,
+            })
+            .collect();
+        Self {
+            content: message.content,
+            tool_calls,
+        }
+    }
+}
+
+/// 流式回复的增量
+pub type ChatCompletionStream =
+    Pin<Box<dyn Stream<Item = Result<ChatCompletionDelta, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// 把流式回复的增量拼接为完整的助手消息。模型按顺序生成工具调用，
+/// 下一个调用开始时上一个调用就已完整，可以立即执行
+#[derive(Debug, Default)]
+pub struct StreamedMessage {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    /// 最后一个调用的 `index`
+    last_index: Option<usize>,
+    /// 已经交出的调用数
+    completed: usize,
+}
+
+impl StreamedMessage {
+    /// 加入一段增量，返回因此变得完整的工具调用
+    pub fn push(&mut self, delta: ChatCompletionDelta) -> Vec<ToolCall> {
+        self.content.push_str(&delta.content);
+        for part in delta.tool_calls {
+            // 有的服务器给每个调用使用相同的 index，只能根据新的 id 判断调用开始
+            let starts_new = match self.tool_calls.last() {
+                None => true,
+                Some(last) => {
+                    self.last_index != Some(part.index)
+                        || part.id.as_ref().is_some_and(|id| !last.id.is_empty() && *id != last.id)
+                }
+            };
+            if starts_new {
+                self.tool_calls.push(ToolCall {
+                    id: String::new(),
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+                self.last_index = Some(part.index);
+            }
+            let call = self.tool_calls.last_mut().expect("刚刚加入了调用");
+            if let Some(id) = part.id {
+                call.id = id;
+            }
+            if let Some(name) = part.name.filter(|name| !name.is_empty()) {
+                call.name = name;
+            }
+            call.arguments.push_str(&part.arguments);
+        }
+        self.take_completed(self.tool_calls.len().saturating_sub(1))
+    }
+
+    /// 回复结束，返回尚未交出的工具调用
+    pub fn finish(&mut self) -> Vec<ToolCall> {
+        self.take_completed(self.tool_calls.len())
+    }
+
+    /// 目前为止的助手消息
+    pub fn message(&self) -> ChatMessage {
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: self.content.clone(),
+            tool_calls: self.tool_calls.clone(),
+            tool_call_id: None,
+        }
+    }
+
+    fn take_completed(&mut self, count: usize) -> Vec<ToolCall> {
+        if count <= self.completed {
+            return Vec::new();
+        }
+        let completed = self.tool_calls[self.completed..count].to_vec();
+        self.completed = count;
+        completed
+    }
+}
+
 /// 语言模型 trait
 #[async_trait]
 pub trait LanguageModel: Send + Sync {
@@ -43,6 +217,21 @@ pub trait LanguageModel: Send + Sync {
         &self,
         request: ChatCompletionRequest,
     ) -> Result<ChatCompletionResponse, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// 以流的形式发送聊天完成请求，逐段返回第一个选择的增量。
+    /// 默认等待完整的回复，再作为一段增量返回
+    async fn chat_completions_stream(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.chat_completions(request).await?;
+        let delta = response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| Ok(ChatCompletionDelta::from(choice.message)));
+        Ok(Box::pin(stream::iter(delta)))
+    }
 }
 
 /// OpenAI 兼容模型
@@ -68,15 +257,12 @@ impl OpenAICompatibleModel {
         
         Self { client, model }
     }
-}
 
-#[async_trait]
-impl LanguageModel for OpenAICompatibleModel {
-    async fn chat_completions(
+    /// 将我们的请求格式转换为 async-openai 格式
+    fn build_request(
         &self,
-        request: ChatCompletionRequest,
-    ) -> Result<ChatCompletionResponse, Box<dyn std::error::Error + Send + Sync>> {
-        // 将我们的请求格式转换为 async-openai 格式
+        request: &ChatCompletionRequest,
+    ) -> Result<CreateChatCompletionRequest, Box<dyn std::error::Error + Send + Sync>> {
         let mut messages = Vec::new();
         
         for msg in &request.messages {
@@ -98,27 +284,72 @@ impl LanguageModel for OpenAICompatibleModel {
                     messages.push(message.into());
                 },
                 "assistant" => {
-                    // 使用正确的格式创建助手消息
-                    let content = msg.content.clone();
+                    // 使用正确的格式创建助手消息，只有工具调用时内容为空
+                    let content = (!msg.content.is_empty() || msg.tool_calls.is_empty()).then(|| msg.content.clone());
+                    let tool_calls = (!msg.tool_calls.is_empty()).then(|| {
+                        msg.tool_calls
+                            .iter()
+                            .map(|call| ChatCompletionMessageToolCall {
+                                id: call.id.clone(),
+                                r#type: ChatCompletionToolType::Function,
+                                function: FunctionCall {
+                                    name: call.name.clone(),
+                                    arguments: call.arguments.clone(),
+                                },
+                            })
+                            .collect()
+                    });
                     let assistant_msg = async_openai::types::ChatCompletionRequestAssistantMessage {
                         role: Role::Assistant,
-                        content: Some(content),
+                        content,
                         name: None,
-                        tool_calls: None,
+                        tool_calls,
                         #[allow(deprecated)]
                         function_call: None,
                     };
                     messages.push(async_openai::types::ChatCompletionRequestMessage::Assistant(assistant_msg));
                 },
+                "tool" => {
+                    let message = ChatCompletionRequestToolMessage {
+                        role: Role::Tool,
+                        content: msg.content.clone(),
+                        tool_call_id: msg.tool_call_id.clone().unwrap_or_default(),
+                    };
+                    messages.push(message.into());
+                },
                 _ => continue, // 跳过未知角色
             }
         }
         
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(&self.model)
-            .messages(messages)
-            .build()?;
-        
+        let mut builder = CreateChatCompletionRequestArgs::default();
+        builder.model(&self.model).messages(messages);
+        if !request.tools.is_empty() {
+            let tools: Vec<ChatCompletionTool> = request
+                .tools
+                .iter()
+                .map(|tool| ChatCompletionTool {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionObject {
+                        name: tool.name.clone(),
+                        description: Some(tool.description.clone()),
+                        parameters: Some(tool.parameters.clone()),
+                    },
+                })
+                .collect();
+            builder.tools(tools);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[async_trait]
+impl LanguageModel for OpenAICompatibleModel {
+    async fn chat_completions(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let request = self.build_request(&request)?;
+
         // 发送请求到 OpenAI API
         let response = self.client.chat().create(request).await?;
         
@@ -127,9 +358,22 @@ impl LanguageModel for OpenAICompatibleModel {
             .choices
             .into_iter()
             .map(|choice| {
+                let tool_calls = choice
+                    .message
+                    .tool_calls
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|call| ToolCall {
+                        id: call.id,
+                        name: call.function.name,
+                        arguments: call.function.arguments,
+                    })
+                    .collect();
                 let message = ChatMessage {
                     role: choice.message.role.to_string(),
                     content: choice.message.content.unwrap_or_default(),
+                    tool_calls,
+                    tool_call_id: None,
                 };
                 
                 ChatChoice { message }
@@ -138,4 +382,150 @@ impl LanguageModel for OpenAICompatibleModel {
         
         Ok(ChatCompletionResponse { choices })
     }
-}
\ No newline at end of file
+
+    /// 通过 SSE 接收回复，工具调用的参数分成多个片段到达
+    async fn chat_completions_stream(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream, Box<dyn std::error::Error + Send + Sync>> {
+        let request = self.build_request(&request)?;
+        let chunks = self.client.chat().create_stream(request).await?;
+
+        let deltas = chunks.map(|chunk| -> Result<ChatCompletionDelta, Box<dyn Error + Send + Sync>> {
+            let Some(choice) = chunk?.choices.into_iter().find(|choice| choice.index == 0) else {
+                return Ok(ChatCompletionDelta::default());
+            };
+            let tool_calls = choice
+                .delta
+                .tool_calls
+                .unwrap_or_default()
+                .into_iter()
+                .map(|call| {
+                    let (name, arguments) = call
+                        .function
+                        .map(|function| (function.name, function.arguments.unwrap_or_default()))
+                        .unwrap_or_default();
+                    ToolCallDelta {
+                        index: call.index as usize,
+                        id: call.id,
+                        name,
+                        arguments,
+                    }
+                })
+                .collect();
+            Ok(ChatCompletionDelta {
+                content: choice.delta.content.unwrap_or_default(),
+                tool_calls,
+            })
+        });
+        Ok(Box::pin(deltas))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(index: usize, id: Option<&str>, name: Option<&str>, arguments: &str) -> ChatCompletionDelta {
+        ChatCompletionDelta {
+            content: String::new(),
+            tool_calls: vec![ToolCallDelta {
+                index,
+                id: id.map(String::from),
+                name: name.map(String::from),
+                arguments: arguments.to_string(),
+            }],
+        }
+    }
+
+    fn text(content: &str) -> ChatCompletionDelta {
+        ChatCompletionDelta {
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fragmented_arguments_are_joined() {
+        let mut reply = StreamedMessage::default();
+        assert!(reply.push(text("好的，")).is_empty());
+        assert!(reply.push(part(0, Some("call_1"), Some("SET_BG_COLOR"), "")).is_empty());
+        assert!(reply.push(part(0, None, None, "{\"col")).is_empty());
+        assert!(reply.push(part(0, None, None, "or\": \"white\"}")).is_empty());
+        assert!(reply.push(text("马上执行")).is_empty());
+
+        // 回复结束时最后一个调用才完整
+        let calls = reply.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].name, "SET_BG_COLOR");
+        assert_eq!(calls[0].arguments, "{\"color\": \"white\"}");
+        assert!(reply.finish().is_empty());
+
+        let message = reply.message();
+        assert_eq!(message.role, "assistant");
+        assert_eq!(message.content, "好的，马上执行");
+        assert_eq!(message.tool_calls.len(), 1);
+    }
+
+    #[test]
+    fn next_index_completes_the_previous_call() {
+        let mut reply = StreamedMessage::default();
+        reply.push(part(0, Some("call_1"), Some("CYCLE_COLOR"), "{}"));
+
+        let calls = reply.push(part(1, Some("call_2"), Some("SET_BG_COLOR"), "{\"color\":"));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+
+        reply.push(part(1, None, None, "\"white\"}"));
+        let calls = reply.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_2");
+        assert_eq!(calls[0].arguments, "{\"color\":\"white\"}");
+        assert_eq!(reply.message().tool_calls.len(), 2);
+    }
+
+    #[test]
+    fn new_id_with_the_same_index_starts_a_new_call() {
+        let mut reply = StreamedMessage::default();
+        reply.push(part(0, Some("call_1"), Some("CYCLE_COLOR"), "{"));
+        // 重复的 id 属于同一个调用
+        assert!(reply.push(part(0, Some("call_1"), None, "}")).is_empty());
+
+        let calls = reply.push(part(0, Some("call_2"), Some("CYCLE_COLOR"), "{}"));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].arguments, "{}");
+
+        let calls = reply.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_2");
+    }
+
+    #[test]
+    fn complete_message_as_one_delta() {
+        let message = ChatMessage {
+            role: "assistant".to_string(),
+            content: String::new(),
+            tool_calls: vec![
+                ToolCall {
+                    id: "call_1".to_string(),
+                    name: "CYCLE_COLOR".to_string(),
+                    arguments: "{}".to_string(),
+                },
+                ToolCall {
+                    id: "call_2".to_string(),
+                    name: "CYCLE_COLOR".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            ],
+            tool_call_id: None,
+        };
+
+        let mut reply = StreamedMessage::default();
+        let mut calls = reply.push(ChatCompletionDelta::from(message));
+        calls.extend(reply.finish());
+        let ids: Vec<&str> = calls.iter().map(|call| call.id.as_str()).collect();
+        assert_eq!(ids, ["call_1", "call_2"]);
+    }
+}
@@ -1,50 +1,56 @@
+mod acp_client;
+mod connection;
 mod llm_interface;
+mod memory;
+mod tools;
+mod transport;
 
+use acp::{
+    discovery::{self, AppEntry},
+    AcpError, CommandInfo, ErrorCode, EventPayload, RequestPayload, ResponseData, ResponsePayload, UiElement,
+    DEFAULT_TCP_ADDR,
+};
+use acp_client::AcpClient;
+use async_trait::async_trait;
+use connection::{ConnectionStatus, ManagedConnection};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     SampleFormat, SampleRate,
 };
-use llm_interface::{ChatCompletionRequest, ChatMessage, LanguageModel, OpenAICompatibleModel};
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use futures::StreamExt;
+use llm_interface::{
+    ChatCompletionRequest, ChatCompletionStream, ChatMessage, LanguageModel, OpenAICompatibleModel, StreamedMessage,
+    ToolCall,
+};
+use memory::ConversationMemory;
+use serde_json::{json, Value};
 use std::{
+    collections::VecDeque,
     env,
     error::Error,
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
+    io::{self, Write},
     path::Path,
     sync::{Arc, Mutex},
     time::Duration,
 };
-use tokio::time::sleep;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::sleep,
+};
+use tools::{build_tools, payload_from_tool_call};
 use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
 
-/// ACP 消息结构体
-#[derive(Debug, Serialize, Deserialize)]
-struct AcpMessage {
-    #[serde(rename = "type")]
-    message_type: String,
-    seq_id: u64,
-    payload: Value,
-}
+/// 每条语音命令最多执行的步骤数，每一步调用一次 LLM
+const MAX_STEPS: usize = 8;
 
-/// 执行动作载荷结构体
-#[derive(Debug, Serialize, Deserialize)]
-struct PerformActionPayload {
-    action: String,
-    command_name: Option<String>,
-    element_id: Option<String>,
-    target_query: Option<String>,
-    params: Option<Value>,
-}
+/// 每个请求最多发送的次数，包括应用暂时无法处理时的重试
+const MAX_ATTEMPTS: usize = 3;
 
-/// ACP 响应载荷结构体
-#[derive(Debug, Serialize, Deserialize)]
-struct AcpResponsePayload {
-    success: bool,
-    message: String,
-}
+/// 对话历史的 token 预算，超出时总结较早的对话
+const HISTORY_TOKEN_BUDGET: usize = 3000;
+
+/// 应用暂时无法处理请求时，重试前等待的时间
+const RETRY_DELAY: Duration = Duration::from_millis(500);
 
 /// 初始化 Whisper 上下文
 fn initialize_whisper(model_path: &str) -> Result<WhisperContext, Box<dyn Error>> {
@@ -192,119 +198,752 @@ fn capture_and_transcribe(whisper_ctx: &WhisperContext) -> Result<String, Box<dy
     Ok(transcription)
 }
 
-/// 使用 LLM 解释命令
-async fn interpret_command_with_llm(
-    llm: Arc<dyn LanguageModel>,
-    transcription: &str,
-) -> Result<String, Box<dyn Error + Send + Sync>> {
-    println!("使用 LLM 解释命令: {}", transcription);
+/// 读取命令行参数 `--<名称> <值>` 或 `--<名称>=<值>`
+fn arg_value(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// 打印运行中的应用，每行一个，带有供用户选择的序号
+fn print_running_apps(apps: &[AppEntry]) {
+    for (index, app) in apps.iter().enumerate() {
+        println!(
+            "  [{}] {} (pid {}): {}",
+            index + 1,
+            app.app_name,
+            app.pid,
+            app.addresses.join(", ")
+        );
+    }
+}
+
+/// 确定要连接的地址。`--addr` 或 `ACP_ADDR` 优先；否则从发现目录中选择运行中的应用，
+/// `--app` 可以按应用名或 pid 指定，有多个候选时让用户选择。
+/// 没有发现任何应用时使用默认地址，以便连接不写发现文件的旧版应用。
+/// 返回地址和所选应用的名称，地址不是从发现目录中选出时名称为 `None`
+fn resolve_acp_addr() -> Result<(String, Option<String>), Box<dyn Error>> {
+    if let Some(addr) = arg_value("addr").or_else(|| env::var("ACP_ADDR").ok()) {
+        return Ok((addr, None));
+    }
+
+    let mut apps = discovery::list_apps(&discovery::discovery_dir());
+    let selector = arg_value("app");
+    if let Some(selector) = &selector {
+        apps.retain(|app| app.matches(selector));
+    }
+
+    let app = match apps.len() {
+        0 => {
+            if let Some(selector) = selector {
+                return Err(format!("没有找到运行中的应用: {}", selector).into());
+            }
+            return Ok((DEFAULT_TCP_ADDR.to_string(), None));
+        }
+        1 => &apps[0],
+        _ => {
+            println!("发现多个运行中的应用:");
+            print_running_apps(&apps);
+            println!("请输入要连接的应用序号:");
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            let index: usize = input.trim().parse().map_err(|_| "无效的序号")?;
+            index
+                .checked_sub(1)
+                .and_then(|i| apps.get(i))
+                .ok_or("无效的序号")?
+        }
+    };
+
+    println!("选择应用 {} (pid {})", app.app_name, app.pid);
+    let addr = app
+        .preferred_address()
+        .ok_or_else(|| format!("应用 {} 没有可用的地址", app.app_name))?;
+    Ok((addr.to_string(), Some(app.app_name.clone())))
+}
+
+/// 重新连接时使用的地址。应用重启后 pid 和端口会变化，原地址从发现目录中选出时，
+/// 原实例已退出且只有一个同名应用在运行就改用它，否则沿用原地址
+fn reconnect_addr(previous: &str, app_name: Option<&str>) -> String {
+    let Some(app_name) = app_name else {
+        return previous.to_string();
+    };
+    let apps = discovery::list_apps(&discovery::discovery_dir());
+    if apps
+        .iter()
+        .any(|app| app.addresses.iter().any(|address| address == previous))
+    {
+        return previous.to_string();
+    }
+
+    let mut candidates = apps.iter().filter(|app| app.app_name == app_name);
+    match (candidates.next(), candidates.next()) {
+        (Some(app), None) => app
+            .preferred_address()
+            .map(str::to_string)
+            .unwrap_or_else(|| previous.to_string()),
+        _ => previous.to_string(),
+    }
+}
+
+/// 查找连接 `addr` 时出示的认证令牌。`ACP_TOKEN` 优先，
+/// 否则读取发现目录中监听该地址的应用写入的令牌文件
+fn find_acp_token(addr: &str) -> Option<String> {
+    if let Ok(token) = env::var("ACP_TOKEN") {
+        return Some(token);
+    }
+    let dir = discovery::discovery_dir();
+    discovery::list_apps(&dir)
+        .into_iter()
+        .find(|app| app.addresses.iter().any(|address| address == addr))
+        .and_then(|app| app.read_token(&dir))
+}
+
+/// 获取目标应用的命令列表，应用不支持 `list_commands` 时退回到握手得到的命令名称
+fn fetch_commands(acp_client: &mut AcpClient) -> Vec<CommandInfo> {
+    match acp_client.list_commands() {
+        Ok(commands) => commands,
+        Err(e) => {
+            println!("警告: 无法获取命令列表: {}", e);
+            acp_client
+                .welcome()
+                .map(|welcome| {
+                    welcome
+                        .commands
+                        .iter()
+                        .map(|name| CommandInfo {
+                            name: name.clone(),
+                            description: String::new(),
+                            params_schema: Value::Null,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// 订阅应用的全部事件，旧版应用不支持时跳过
+fn subscribe_events(acp_client: &mut AcpClient) {
+    if !acp_client
+        .welcome()
+        .is_some_and(|welcome| welcome.supports_action("subscribe"))
+    {
+        return;
+    }
+    if let Err(e) = acp_client.subscribe(Vec::new()) {
+        println!("警告: {}", e);
+    }
+}
+
+/// 打印目前收到的应用事件
+fn print_events(acp_client: &AcpClient) {
+    for event in acp_client.take_events() {
+        match event.payload {
+            EventPayload::StateChanged { state } => println!("应用状态已变化: {}", state),
+            EventPayload::ElementAdded { parent_id, element } => println!(
+                "界面新增元素: {} ({})，父元素: {}",
+                element.id,
+                element.role,
+                parent_id.as_deref().unwrap_or("无")
+            ),
+            EventPayload::FocusChanged { element_id } => {
+                println!("焦点已移动到: {}", element_id.as_deref().unwrap_or("无"))
+            }
+            EventPayload::WindowClosed => println!("目标应用的窗口已关闭"),
+        }
+    }
+}
+
+/// 根据当前界面元素树构建系统提示。命令和元素操作作为工具提供，每一步重新构建，
+/// 模型因此总能看到上一步执行后的界面
+fn build_system_prompt(ui_tree: Option<&UiElement>, atomic_batch: bool) -> String {
+    let mut prompt = String::from(
+        "您是一个 AI 助手，正在帮助用户控制一个桌面应用程序。
+应用的命令和界面元素操作以工具的形式提供。
+如果用户的语音命令（转录文本）表明了想要执行其中某个命令或操作某个界面元素的意图，请调用对应的工具。
+工具需要参数时，从用户的命令中提取参数值，并满足工具的参数说明，例如把颜色名称换成参数中可用的值。
+之前的对话记录了用户较早的命令和执行结果，可以据此理解\"再来一次\"、\"撤销\"等说法。\n",
+    );
+
+    if let Some(ui_tree) = ui_tree {
+        if let Ok(tree_json) = serde_json::to_string(ui_tree) {
+            prompt.push_str(&format!("应用当前的界面元素树（JSON）: {}\n", tree_json));
+        }
+    }
+
+    prompt.push_str(&format!(
+        "命令会分步执行，最多 {} 步。每次调用工具后，您会收到执行结果，上面的界面元素树也会更新为执行后的界面。
+请根据结果和界面判断用户的目标是否已经达成：未达成时继续调用工具；
+已经达成或无法达成时不要调用工具，用一句话说明结果。
+需要根据执行结果决定下一步时（例如循环切换直到出现某个颜色），每次只调用一个工具。\n",
+        MAX_STEPS
+    ));
+    if atomic_batch {
+        prompt.push_str(
+            "如果确定同一步需要多个操作，可以按执行顺序调用多个工具。
+这些调用会作为一个整体执行，任一调用失败时全部撤销。\n",
+        );
+    } else {
+        prompt.push_str(
+            "如果确定同一步需要多个操作，可以按执行顺序调用多个工具。
+每个调用生成后立即执行，某个调用失败时后面的调用不再执行，已经执行的调用不会撤销。\n",
+        );
+    }
+    prompt.push_str("如果用户的意图不明确或不相关，不要调用任何工具。");
+    prompt
+}
+
+/// 请求失败后 agent 的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// 稍后原样重发请求
+    Retry,
+    /// 把错误交给 LLM，重新生成请求
+    Replan,
+    /// 无法自动恢复，告知用户
+    Report,
+}
 
+/// 根据错误码决定如何处理失败的请求
+fn recovery_for(error: &AcpError) -> Recovery {
+    match error.code {
+        ErrorCode::ServerBusy | ErrorCode::NotRendered => Recovery::Retry,
+        ErrorCode::UnknownCommand
+        | ErrorCode::InvalidParams
+        | ErrorCode::UnknownElement
+        | ErrorCode::AmbiguousTarget
+        | ErrorCode::UnsupportedAction => Recovery::Replan,
+        _ => Recovery::Report,
+    }
+}
+
+/// 请 LLM 根据之前的对话、本条命令目前的对话和界面决定下一步，返回模型回复的流。
+/// 回复中没有工具调用表示命令已经完成或无法执行
+async fn plan_next_step(
+    llm: Arc<dyn LanguageModel>,
+    history: &[ChatMessage],
+    turn: &[ChatMessage],
+    commands: &[CommandInfo],
+    ui_tree: Option<&UiElement>,
+    atomic_batch: bool,
+) -> Result<ChatCompletionStream, Box<dyn Error + Send + Sync>> {
     // 系统提示
-    let system_prompt = "您是一个 AI 助手，正在帮助用户控制一个桌面应用程序。
-该应用程序有一个按钮，点击它可以循环改变背景颜色。
-如果用户的语音命令（转录文本）表明了想要改变背景颜色或点击此按钮的意图，
-请仅输出命令字符串 'CYCLE_COLOR_COMMAND'。
-不要添加任何其他文本、解释或客套话。只需要这个命令字符串。
-如果用户的意图不明确或不相关，请输出 'UNKNOWN_COMMAND'。";
+    let system_prompt = build_system_prompt(ui_tree, atomic_batch);
 
     // 构建请求
+    let mut messages = vec![ChatMessage::system(system_prompt)];
+    messages.extend_from_slice(history);
+    messages.extend_from_slice(turn);
     let request = ChatCompletionRequest {
         model: "gpt-3.5-turbo".to_string(), // 可以配置为其他模型
-        messages: vec![
-            ChatMessage {
-                role: "system".to_string(),
-                content: system_prompt.to_string(),
-            },
-            ChatMessage {
-                role: "user".to_string(),
-                content: transcription.to_string(),
-            },
-        ],
+        messages,
+        tools: build_tools(commands),
     };
 
     // 发送请求给 LLM
-    let response = llm.chat_completions(request).await?;
-
-    // 解析响应
-    if let Some(choice) = response.choices.first() {
-        let command = choice.message.content.trim();
-        
-        if command == "CYCLE_COLOR_COMMAND" || command == "UNKNOWN_COMMAND" {
-            return Ok(command.to_string());
-        } else {
-            println!("LLM 返回了意外的响应: {}", command);
-            return Ok("UNKNOWN_COMMAND".to_string());
+    llm.chat_completions_stream(request).await
+}
+
+/// 连接目标应用并订阅事件，首次连接和重新连接共用。应用重启后令牌会变化，每次连接都重新读取
+fn connect_to_app(addr: &str) -> Result<AcpClient, Box<dyn Error>> {
+    let mut client = AcpClient::connect(addr, find_acp_token(addr).as_deref())?;
+    subscribe_events(&mut client);
+    Ok(client)
+}
+
+/// 重新连接后应用可能已重启，重新获取命令列表
+async fn refresh_commands(
+    connection: &ManagedConnection,
+    commands: &mut Vec<CommandInfo>,
+    generation: &mut u64,
+) {
+    let current = connection.generation();
+    if current == *generation {
+        return;
+    }
+    if let Some(fetched) = connection.with_client(fetch_commands).await {
+        *commands = fetched;
+        *generation = current;
+    }
+}
+
+/// 在后台线程中逐行读取标准输入，主循环因此可以在等待输入时显示连接状态的变化
+fn spawn_input_reader() -> UnboundedReceiver<String> {
+    let (sender, receiver) = unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// 一条命令的执行结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommandOutcome {
+    /// 已执行完毕，或者无法执行、已告知用户
+    Finished,
+    /// 与应用的连接已断开，还没有向应用发送过请求，可以在重新连接后重新执行
+    Disconnected,
+    /// 向应用发送请求之后连接断开，命令可能已经部分执行，不会重新执行
+    Interrupted,
+}
+
+/// 模型的文字回复逐段到达时立即显示
+fn print_reply_text(text: &str, printing: &mut bool) {
+    // 开头的空白不显示
+    let text = if *printing { text } else { text.trim_start() };
+    if text.is_empty() {
+        return;
+    }
+    if !*printing {
+        print!("LLM: ");
+        *printing = true;
+    }
+    print!("{}", text);
+    let _ = io::stdout().flush();
+}
+
+/// 结束正在显示的文字回复，之后的输出另起一行
+fn end_reply_text(printing: &mut bool) {
+    if *printing {
+        println!();
+        *printing = false;
+    }
+}
+
+/// 一组工具调用的执行结果，附带交给模型的工具结果，每个工具调用一条
+enum CallOutcome {
+    Succeeded(Vec<ChatMessage>),
+    /// 执行失败，交给模型重新规划
+    Failed(Vec<ChatMessage>),
+    /// 遇到无法自动处理的错误，已告知用户
+    Stop(Vec<ChatMessage>),
+    /// 与应用的连接已断开
+    Disconnected,
+}
+
+/// 为每个工具调用生成内容相同的工具结果
+fn same_result_for_all(calls: &[ToolCall], content: &str) -> Vec<ChatMessage> {
+    calls
+        .iter()
+        .map(|call| ChatMessage::tool_result(call.id.clone(), content))
+        .collect()
+}
+
+/// 打印批处理中每一项的结果
+fn print_batch_results(response: &ResponsePayload) {
+    if let Some(ResponseData::Batch { results }) = &response.data {
+        for (index, result) in results.iter().enumerate() {
+            println!(
+                "  第 {} 项{}: {}",
+                index + 1,
+                if result.success { "成功" } else { "失败" },
+                result.message
+            );
+        }
+    }
+}
+
+/// 请求失败的原因。客户端返回的错误不能在线程间传递，在执行请求的线程中先行区分
+enum RequestFailure {
+    /// 客户端或应用给出的 ACP 错误，连接仍然可用
+    Acp(AcpError),
+    /// 连接出错
+    Connection(String),
+}
+
+impl From<Box<dyn Error>> for RequestFailure {
+    fn from(error: Box<dyn Error>) -> Self {
+        match error.downcast::<AcpError>() {
+            Ok(error) => RequestFailure::Acp(*error),
+            Err(error) => RequestFailure::Connection(error.to_string()),
         }
+    }
+}
+
+/// 执行模型请求的一组工具调用，多个调用作为原子批处理。
+/// 应用暂时无法处理时原样重发，其他错误作为工具结果交给模型重新规划。
+/// 请求可能已经到达应用时把 `sent` 置为 `true`
+async fn act(
+    calls: &[ToolCall],
+    connection: &ManagedConnection,
+    commands: &mut Vec<CommandInfo>,
+    sent: &mut bool,
+) -> CallOutcome {
+    // 任一工具调用无法转换时整组都不执行
+    let mut steps = match calls
+        .iter()
+        .map(|call| payload_from_tool_call(call, commands))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(steps) => steps,
+        Err(e) => {
+            println!("LLM 返回了无法执行的工具调用: {}", e);
+            let content = json!({ "success": false, "message": format!("工具调用无效，没有执行: {}", e) });
+            return CallOutcome::Failed(same_result_for_all(calls, &content.to_string()));
+        }
+    };
+    let payload = if steps.len() == 1 {
+        steps.remove(0)
     } else {
-        println!("LLM 没有返回选择");
-        return Ok("UNKNOWN_COMMAND".to_string());
-    }
-}
-
-/// 发送 ACP 请求
-fn send_acp_request(
-    stream: &mut TcpStream,
-    command_name: &str,
-) -> Result<AcpResponsePayload, Box<dyn Error>> {
-    // 生成随机序列 ID
-    let seq_id = rand::thread_rng().gen::<u64>();
-
-    // 构建执行动作载荷
-    let payload = PerformActionPayload {
-        action: "custom_command".to_string(),
-        command_name: Some(command_name.to_string()),
-        element_id: None,
-        target_query: None,
-        params: None,
+        RequestPayload::Batch {
+            requests: steps,
+            atomic: true,
+        }
     };
 
-    // 构建 ACP 消息
-    let acp_message = AcpMessage {
-        message_type: "request".to_string(),
-        seq_id,
-        payload: serde_json::to_value(payload).map_err(|e| format!("序列化载荷失败: {}", e))?,
+    let mut attempt = 1;
+    let response = loop {
+        println!("发送 {} 请求到 target_gpui_app", payload.action_name());
+        let request = payload.clone();
+        let result = connection
+            .with_client(move |client| client.request(request).map_err(RequestFailure::from))
+            .await;
+        let Some(result) = result else {
+            return CallOutcome::Disconnected;
+        };
+        let response = match result {
+            Ok(response) => {
+                *sent = true;
+                response
+            }
+            // 客户端在发送前就拒绝了请求，或者等待响应超时。超时的请求可能已经执行，
+            // 但连接仍然可用，作为失败的调用处理
+            Err(RequestFailure::Acp(error)) => {
+                if error.code == ErrorCode::TimedOut {
+                    *sent = true;
+                }
+                ResponsePayload::failure(error)
+            }
+            Err(RequestFailure::Connection(e)) => {
+                println!("发送 ACP 请求失败: {}", e);
+                *sent = true;
+                connection.mark_lost();
+                return CallOutcome::Disconnected;
+            }
+        };
+        println!(
+            "命令执行 {}: {}",
+            if response.success { "成功" } else { "失败" },
+            response.message
+        );
+        print_batch_results(&response);
+        connection.with_client(|client| print_events(client)).await;
+
+        match response.error.as_ref().filter(|_| !response.success).map(recovery_for) {
+            Some(Recovery::Retry) if attempt < MAX_ATTEMPTS => {
+                println!("应用暂时无法处理请求，稍后重试 ({}/{})", attempt, MAX_ATTEMPTS);
+                sleep(RETRY_DELAY).await;
+                attempt += 1;
+            }
+            _ => break response,
+        }
     };
 
-    // 将 ACP 消息转换为 JSON 字符串，并添加换行符
-    let message_json = serde_json::to_string(&acp_message).map_err(|e| format!("序列化 ACP 消息失败: {}", e))?;
-    let message_with_newline = format!("{}\n", message_json);
+    let mut stop = false;
+    if let Some(error) = response.error.as_ref().filter(|_| !response.success) {
+        match recovery_for(error) {
+            Recovery::Report => {
+                println!("无法自动处理该错误，请检查目标应用后重试: {}", error);
+                stop = true;
+            }
+            Recovery::Replan => {
+                println!("根据错误 {} 重新规划", error.code);
+                if error.code == ErrorCode::UnknownCommand {
+                    if let Some(fetched) = connection.with_client(fetch_commands).await {
+                        *commands = fetched;
+                    }
+                }
+            }
+            Recovery::Retry => {}
+        }
+    }
 
-    println!("发送 ACP 请求: {}", message_with_newline);
+    // 批处理的每一项对应一个工具调用，没有执行到的调用使用整体的结果
+    let overall = serde_json::to_string(&response).unwrap_or_default();
+    let results = calls
+        .iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let content = match &response.data {
+                Some(ResponseData::Batch { results }) if calls.len() > 1 => results
+                    .get(index)
+                    .and_then(|result| serde_json::to_string(result).ok())
+                    .unwrap_or_else(|| overall.clone()),
+                _ => overall.clone(),
+            };
+            ChatMessage::tool_result(call.id.clone(), content)
+        })
+        .collect();
+    if stop {
+        CallOutcome::Stop(results)
+    } else if response.success {
+        CallOutcome::Succeeded(results)
+    } else {
+        CallOutcome::Failed(results)
+    }
+}
+
+/// 分步执行一条命令，执行完毕后记入对话历史。断线的命令不记入历史
+async fn execute_command(
+    transcription: &str,
+    llm: &Arc<OpenAICompatibleModel>,
+    connection: &ManagedConnection,
+    commands: &mut Vec<CommandInfo>,
+    memory: &mut ConversationMemory,
+    atomic_batch: bool,
+) -> CommandOutcome {
+    // 心跳发现断开之前，读线程可能已经知道连接被关闭，不必先调用 LLM
+    if connection.with_client(|client| client.is_closed()).await.unwrap_or(true) {
+        connection.mark_lost();
+        return CommandOutcome::Disconnected;
+    }
+
+    println!("使用 LLM 执行命令: {}", transcription);
+    let history = memory.messages();
+    let mut turn = vec![ChatMessage::user(transcription)];
+    let outcome = run_steps(llm, connection, commands, &history, &mut turn, atomic_batch).await;
+    if outcome == CommandOutcome::Finished {
+        memory.record_turn(turn);
+        memory.compact(llm.clone()).await;
+    }
+    outcome
+}
+
+/// 断线时命令的执行结果。已经发送过请求的命令可能部分执行，不能重新执行
+fn disconnected(sent: bool) -> CommandOutcome {
+    if sent {
+        CommandOutcome::Interrupted
+    } else {
+        CommandOutcome::Disconnected
+    }
+}
+
+/// 执行模型请求的工具调用。读取回复的流程与执行方式分开，测试中可以替换执行方式
+#[async_trait]
+trait CallRunner {
+    /// 执行一组工具调用，多个调用作为原子批处理
+    async fn run(&mut self, calls: &[ToolCall]) -> CallOutcome;
+}
 
-    // 发送 ACP 请求
-    stream
-        .write_all(message_with_newline.as_bytes())
-        .map_err(|e| format!("发送 ACP 请求失败: {}", e))?;
+/// 通过 ACP 在目标应用上执行工具调用
+struct AppCallRunner<'a> {
+    connection: &'a ManagedConnection,
+    commands: &'a mut Vec<CommandInfo>,
+    /// 是否已经有请求可能到达应用，决定断线后能否重新执行
+    sent: bool,
+}
 
-    // 读取 ACP 响应
-    let mut reader = BufReader::new(stream);
-    let mut response_line = String::new();
-    reader
-        .read_line(&mut response_line)
-        .map_err(|e| format!("读取 ACP 响应失败: {}", e))?;
+#[async_trait]
+impl CallRunner for AppCallRunner<'_> {
+    async fn run(&mut self, calls: &[ToolCall]) -> CallOutcome {
+        act(calls, self.connection, self.commands, &mut self.sent).await
+    }
+}
 
-    println!("收到 ACP 响应: {}", response_line);
+/// 模型的一次回复，以及其中工具调用的执行结果
+struct StepReply {
+    message: ChatMessage,
+    /// 交给模型的工具结果，每个工具调用一条
+    results: Vec<ChatMessage>,
+    /// 遇到无法自动处理的错误或回复中断，不再继续执行这条命令
+    stop: bool,
+}
 
-    // 解析 ACP 响应
-    let acp_response: AcpMessage = serde_json::from_str(&response_line)
-        .map_err(|e| format!("解析 ACP 响应失败: {}", e))?;
+/// 读取模型的回复并执行其中的工具调用。默认每个调用完整后立即执行，不等待回复结束；
+/// `atomic_batch` 为 `true` 时在回复结束后把全部调用作为一个原子批处理执行。
+/// 某个调用失败后，后面的调用不再执行。与应用的连接断开时返回 `None`
+async fn act_on_reply(
+    mut stream: ChatCompletionStream,
+    step: usize,
+    atomic_batch: bool,
+    runner: &mut impl CallRunner,
+) -> Option<StepReply> {
+    let mut reply = StreamedMessage::default();
+    let mut queued = Vec::new();
+    let mut results = Vec::new();
+    let mut skip_reason = None;
+    let mut stop = false;
+    let mut printing_text = false;
+    loop {
+        let (calls, finished) = match stream.next().await {
+            Some(Ok(delta)) => {
+                print_reply_text(&delta.content, &mut printing_text);
+                (reply.push(delta), false)
+            }
+            Some(Err(e)) => {
+                end_reply_text(&mut printing_text);
+                println!("LLM 回复中断: {}", e);
+                // 最后一个调用可能不完整
+                skip_reason.get_or_insert("LLM 回复中断，没有执行");
+                stop = true;
+                (reply.finish(), true)
+            }
+            None => (reply.finish(), true),
+        };
 
-    // 检查序列 ID 是否匹配
-    if acp_response.seq_id != seq_id {
-        return Err(format!("ACP 响应序列 ID 不匹配: 预期 {}, 实际 {}", seq_id, acp_response.seq_id).into());
+        queued.extend(calls);
+        if !queued.is_empty() && (finished || !atomic_batch) {
+            end_reply_text(&mut printing_text);
+            if results.is_empty() {
+                println!("第 {}/{} 步:", step, MAX_STEPS);
+            }
+            let calls = std::mem::take(&mut queued);
+            let groups: Vec<Vec<ToolCall>> = if atomic_batch {
+                vec![calls]
+            } else {
+                calls.into_iter().map(|call| vec![call]).collect()
+            };
+            for group in groups {
+                if let Some(reason) = skip_reason {
+                    let content = json!({ "success": false, "message": reason });
+                    results.extend(same_result_for_all(&group, &content.to_string()));
+                    continue;
+                }
+                match runner.run(&group).await {
+                    CallOutcome::Succeeded(group_results) => results.extend(group_results),
+                    CallOutcome::Failed(group_results) => {
+                        results.extend(group_results);
+                        skip_reason = Some("前面的工具调用失败，没有执行");
+                    }
+                    CallOutcome::Stop(group_results) => {
+                        results.extend(group_results);
+                        skip_reason = Some("前面的工具调用失败，没有执行");
+                        stop = true;
+                    }
+                    CallOutcome::Disconnected => return None,
+                }
+            }
+        }
+        if finished {
+            break;
+        }
     }
+    end_reply_text(&mut printing_text);
+
+    Some(StepReply {
+        message: reply.message(),
+        results,
+        stop,
+    })
+}
+
+/// 观察界面，请 LLM 决定下一步，执行后再次观察，直到模型认为目标已经达成或无法达成，
+/// 或者用完步骤数。本条命令的对话追加到 `turn`。
+/// `atomic_batch` 要求把同一回复中的工具调用作为原子批处理执行，应用不支持批处理时逐个执行
+async fn run_steps(
+    llm: &Arc<OpenAICompatibleModel>,
+    connection: &ManagedConnection,
+    commands: &mut Vec<CommandInfo>,
+    history: &[ChatMessage],
+    turn: &mut Vec<ChatMessage>,
+    atomic_batch: bool,
+) -> CommandOutcome {
+    let mut runner = AppCallRunner {
+        connection,
+        commands,
+        sent: false,
+    };
+    for step in 1..=MAX_STEPS {
+        // 观察: 获取当前界面，帮助 LLM 理解上下文并检查上一步的效果
+        let ui_tree = connection
+            .with_client(|client| client.get_ui_tree().map_err(|e| e.to_string()))
+            .await;
+        let Some(ui_tree) = ui_tree else {
+            return disconnected(runner.sent);
+        };
+        let ui_tree = match ui_tree {
+            Ok(root) => Some(root),
+            Err(e) => {
+                println!("警告: {}", e);
+                None
+            }
+        };
+
+        let supports_batch = connection
+            .with_client(|client| {
+                client
+                    .welcome()
+                    .is_some_and(|welcome| welcome.supports_action("batch"))
+            })
+            .await
+            .unwrap_or(false);
+        if atomic_batch && !supports_batch && step == 1 {
+            println!("目标应用不支持批处理，工具调用将逐个执行");
+        }
+        let atomic_batch = atomic_batch && supports_batch;
+
+        // 规划: 模型的回复以流的形式到达
+        let commands = &*runner.commands;
+        let stream = plan_next_step(llm.clone(), history, turn, commands, ui_tree.as_ref(), atomic_batch).await;
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("LLM 解释失败: {}", e);
+                return CommandOutcome::Finished;
+            }
+        };
 
-    // 解析响应载荷
-    let response_payload: AcpResponsePayload = serde_json::from_value(acp_response.payload)
-        .map_err(|e| format!("解析响应载荷失败: {}", e))?;
+        // 执行: 默认每个工具调用完整后立即执行
+        let Some(reply) = act_on_reply(stream, step, atomic_batch, &mut runner).await else {
+            return disconnected(runner.sent);
+        };
 
-    Ok(response_payload)
+        let acted = !reply.message.tool_calls.is_empty();
+        let replied = !reply.message.content.trim().is_empty();
+        turn.push(reply.message);
+        turn.extend(reply.results);
+        if reply.stop {
+            return CommandOutcome::Finished;
+        }
+        if !acted {
+            match (step, replied) {
+                (1, false) => println!("未知命令或意图不明确"),
+                (1, true) => println!("LLM 没有调用工具"),
+                _ => println!("命令已执行完毕"),
+            }
+            return CommandOutcome::Finished;
+        }
+    }
+
+    println!("已执行 {} 步仍未完成，停止执行该命令", MAX_STEPS);
+    CommandOutcome::Finished
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     println!("=== AgentKit Layer 启动 ===");
 
+    // --list 只列出运行中的应用
+    if env::args().any(|arg| arg == "--list") {
+        let apps = discovery::list_apps(&discovery::discovery_dir());
+        if apps.is_empty() {
+            println!("没有发现运行中的应用");
+        } else {
+            print_running_apps(&apps);
+        }
+        return Ok(());
+    }
+
+    // --atomic-batch 把同一回复中的工具调用作为原子批处理执行，默认每个调用生成后立即执行
+    let atomic_batch = env::args().any(|arg| arg == "--atomic-batch");
+
     // 检查 OpenAI API 密钥是否存在
     let api_key = env::var("OPENAI_API_KEY").ok();
     if api_key.is_none() {
@@ -345,33 +984,102 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let llm = Arc::new(OpenAICompatibleModel::new(api_key, base_url, model_name));
     println!("LLM 初始化完成");
 
-    // 连接到 target_gpui_app
-    let mut tcp_stream = match TcpStream::connect("127.0.0.1:7880") {
-        Ok(stream) => {
+    // 连接到 target_gpui_app，地址可以是 主机:端口、unix:<套接字路径> 或 ws://主机:端口/
+    let (acp_addr, app_name) = resolve_acp_addr()?;
+    println!("连接到 {}", acp_addr);
+    let acp_client = match connect_to_app(&acp_addr) {
+        Ok(client) => {
             println!("已连接到 target_gpui_app");
-            stream
+            client
         }
         Err(e) => {
             return Err(format!("无法连接到 target_gpui_app: {}", e).into());
         }
     };
 
+    // 后台线程负责心跳和断线重连，应用重启后地址可能变化，每次重新连接前重新查找
+    let connection = ManagedConnection::start(acp_client, move || {
+        connect_to_app(&reconnect_addr(&acp_addr, app_name.as_deref()))
+    });
+    let mut status_updates = connection.watch_status();
+    let mut was_connected = true;
+
+    // 获取应用暴露的命令，用于构建提示和校验 LLM 输出
+    let mut commands = connection.with_client(fetch_commands).await.unwrap_or_default();
+    let mut commands_generation = connection.generation();
+    if commands.is_empty() {
+        println!("警告: 目标应用没有可用的命令");
+    }
+
+    // 断线期间收到的命令，重新连接后各重新执行一次
+    let mut pending: VecDeque<String> = VecDeque::new();
+    let mut memory = ConversationMemory::new(HISTORY_TOKEN_BUDGET);
+    let mut input_lines = spawn_input_reader();
+
     // 主循环
-    loop {
+    'repl: loop {
+        // 显示等待输入期间应用推送的事件
+        connection.with_client(|client| print_events(client)).await;
+
+        if connection.status() == ConnectionStatus::Connected {
+            while let Some(transcription) = pending.pop_front() {
+                println!("\n重新执行断线期间的命令: {}", transcription);
+                refresh_commands(&connection, &mut commands, &mut commands_generation).await;
+                let outcome =
+                    execute_command(&transcription, &llm, &connection, &mut commands, &mut memory, atomic_batch)
+                        .await;
+                if outcome != CommandOutcome::Finished {
+                    println!("连接再次断开，放弃执行该命令: {}", transcription);
+                }
+            }
+        }
+
+        let status = connection.status();
         if whisper_ctx.is_some() {
-            println!("\n按 Enter 开始语音识别，或输入 'quit' 退出，或直接输入命令:");
+            println!(
+                "\n[{}] 按 Enter 开始语音识别，或输入 'quit' 退出，'status' 查看连接状态，'clear' 清空对话历史，或直接输入命令:",
+                status
+            );
         } else {
-            println!("\n输入命令或 'quit' 退出:");
+            println!("\n[{}] 输入命令，'status' 查看连接状态，'clear' 清空对话历史，或 'quit' 退出:", status);
         }
-        
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        
+
+        // 等待输入期间连接断开或恢复时立即显示
+        let input = loop {
+            tokio::select! {
+                line = input_lines.recv() => break line,
+                Ok(()) = status_updates.changed() => {
+                    let status = *status_updates.borrow_and_update();
+                    let connected = status == ConnectionStatus::Connected;
+                    if connected != was_connected {
+                        was_connected = connected;
+                        println!("\nACP 连接状态: {}", status);
+                        // 重新连接后回到循环开头，执行排队的命令并重新显示提示
+                        if connected {
+                            continue 'repl;
+                        }
+                    }
+                }
+            }
+        };
+        let Some(input) = input else {
+            break;
+        };
+
         let input = input.trim();
-        
+
         if input.eq_ignore_ascii_case("quit") {
             break;
         }
+        if input.eq_ignore_ascii_case("status") {
+            println!("ACP 连接状态: {}，等待重新执行的命令: {} 条", connection.status(), pending.len());
+            continue;
+        }
+        if input.eq_ignore_ascii_case("clear") {
+            memory.clear();
+            println!("已清空对话历史");
+            continue;
+        }
 
         // 获取转录文本，要么通过语音识别，要么通过手动输入
         let transcription = if input.is_empty() && whisper_ctx.is_some() {
@@ -380,17 +1088,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 Ok(text) => text,
                 Err(e) => {
                     println!("转录音频失败: {}。请手动输入命令:", e);
-                    let mut manual_input = String::new();
-                    std::io::stdin().read_line(&mut manual_input)?;
-                    manual_input.trim().to_string()
+                    input_lines.recv().await.unwrap_or_default().trim().to_string()
                 }
             }
         } else if input.is_empty() {
             // 当没有Whisper模型但用户按下Enter时，提醒用户
             println!("语音识别不可用。请手动输入命令:");
-            let mut manual_input = String::new();
-            std::io::stdin().read_line(&mut manual_input)?;
-            manual_input.trim().to_string()
+            input_lines.recv().await.unwrap_or_default().trim().to_string()
         } else {
             // 用户直接输入了文本命令
             input.to_string()
@@ -401,44 +1105,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
             continue;
         }
 
-        // 使用 LLM 解释命令
-        let command = match interpret_command_with_llm(llm.clone(), &transcription).await {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                println!("LLM 解释失败: {}", e);
-                continue;
-            }
-        };
+        if connection.status() != ConnectionStatus::Connected {
+            println!("目标应用尚未连接，命令将在重新连接后执行: {}", transcription);
+            pending.push_back(transcription);
+            continue;
+        }
 
-        // 处理命令
-        if command == "CYCLE_COLOR_COMMAND" {
-            println!("发送 CYCLE_COLOR 命令到 target_gpui_app");
-            
-            match send_acp_request(&mut tcp_stream, "CYCLE_COLOR") {
-                Ok(response) => {
-                    println!(
-                        "命令执行 {}: {}",
-                        if response.success { "成功" } else { "失败" },
-                        response.message
-                    );
-                }
-                Err(e) => {
-                    println!("发送 ACP 请求失败: {}", e);
-                    // 尝试重新连接
-                    println!("尝试重新连接到 target_gpui_app...");
-                    match TcpStream::connect("127.0.0.1:7880") {
-                        Ok(stream) => {
-                            println!("重新连接成功");
-                            tcp_stream = stream;
-                        }
-                        Err(e) => {
-                            println!("重新连接失败: {}", e);
-                        }
-                    }
-                }
+        refresh_commands(&connection, &mut commands, &mut commands_generation).await;
+        match execute_command(&transcription, &llm, &connection, &mut commands, &mut memory, atomic_batch).await {
+            CommandOutcome::Finished => {}
+            CommandOutcome::Disconnected => {
+                println!("与目标应用的连接已断开，命令将在重新连接后重新执行一次: {}", transcription);
+                pending.push_back(transcription);
+            }
+            CommandOutcome::Interrupted => {
+                println!(
+                    "与目标应用的连接在执行过程中断开，命令可能已部分执行，不会自动重新执行: {}",
+                    transcription
+                );
             }
-        } else {
-            println!("未知命令或意图不明确");
         }
 
         // 添加短暂延迟以避免 CPU 使用率过高
@@ -447,4 +1132,88 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("=== AgentKit Layer 已退出 ===");
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use llm_interface::{ChatCompletionDelta, ToolCallDelta};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 记录每组工具调用的名称，以及执行时已经读到的回复段数
+    struct RecordingRunner {
+        read: Arc<AtomicUsize>,
+        runs: Vec<(Vec<String>, usize)>,
+    }
+
+    #[async_trait]
+    impl CallRunner for RecordingRunner {
+        async fn run(&mut self, calls: &[ToolCall]) -> CallOutcome {
+            let names = calls.iter().map(|call| call.name.clone()).collect();
+            self.runs.push((names, self.read.load(Ordering::SeqCst)));
+            CallOutcome::Succeeded(same_result_for_all(calls, "{\"success\":true}"))
+        }
+    }
+
+    fn call(index: usize, name: &str) -> ChatCompletionDelta {
+        ChatCompletionDelta {
+            content: String::new(),
+            tool_calls: vec![ToolCallDelta {
+                index,
+                id: Some(format!("call_{}", index)),
+                name: Some(name.to_string()),
+                arguments: "{}".to_string(),
+            }],
+        }
+    }
+
+    /// 依次给出 `deltas` 的回复，记录已经读到的段数
+    fn reply(deltas: Vec<ChatCompletionDelta>, read: &Arc<AtomicUsize>) -> ChatCompletionStream {
+        let read = read.clone();
+        Box::pin(stream::iter(deltas).map(move |delta| {
+            read.fetch_add(1, Ordering::SeqCst);
+            Ok(delta)
+        }))
+    }
+
+    async fn act_on(atomic_batch: bool) -> (Vec<(Vec<String>, usize)>, StepReply) {
+        let read = Arc::new(AtomicUsize::new(0));
+        let deltas = vec![call(0, "CYCLE_COLOR"), call(1, "SET_BG_COLOR"), call(2, "CYCLE_COLOR")];
+        let mut runner = RecordingRunner {
+            read: read.clone(),
+            runs: Vec::new(),
+        };
+        let reply = act_on_reply(reply(deltas, &read), 1, atomic_batch, &mut runner)
+            .await
+            .expect("没有断开连接");
+        (runner.runs, reply)
+    }
+
+    #[tokio::test]
+    async fn calls_run_before_the_reply_finishes() {
+        let (runs, reply) = act_on(false).await;
+
+        // 下一个调用开始时上一个调用就已完整，此时回复还没有读完
+        assert_eq!(
+            runs,
+            vec![
+                (vec!["CYCLE_COLOR".to_string()], 2),
+                (vec!["SET_BG_COLOR".to_string()], 3),
+                (vec!["CYCLE_COLOR".to_string()], 3),
+            ]
+        );
+        assert_eq!(reply.message.tool_calls.len(), 3);
+        assert_eq!(reply.results.len(), 3);
+        assert!(!reply.stop);
+    }
+
+    #[tokio::test]
+    async fn atomic_batch_waits_for_the_whole_reply() {
+        let (runs, reply) = act_on(true).await;
+
+        let names = ["CYCLE_COLOR", "SET_BG_COLOR", "CYCLE_COLOR"].map(String::from).to_vec();
+        assert_eq!(runs, vec![(names, 3)]);
+        assert_eq!(reply.results.len(), 3);
+    }
+}
@@ -0,0 +1,303 @@
+//! 跨语音命令的对话记忆
+//!
+//! 每条命令的对话（用户的话、工具调用、执行结果和模型的回复）作为一轮保存，
+//! 之后的命令因此可以理解"再来一次"、"撤销"等说法。
+//! 估计的 token 数超过预算时，较早的轮次由 LLM 总结为摘要。
+
+use crate::llm_interface::{ChatCompletionRequest, ChatMessage, LanguageModel};
+use std::{error::Error, sync::Arc};
+
+/// 总结时保留原文的最近轮数
+const KEEP_RECENT_TURNS: usize = 2;
+
+/// 每条消息除内容外的固定开销，单位为 token
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// 有上限的对话历史
+pub struct ConversationMemory {
+    /// 已被总结的较早轮次的摘要
+    summary: Option<String>,
+    /// 尚未总结的轮次，每一轮以用户消息开头，工具结果紧跟对应的工具调用
+    turns: Vec<Vec<ChatMessage>>,
+    token_budget: usize,
+}
+
+impl ConversationMemory {
+    /// 创建空的对话历史，估计的 token 数超过 `token_budget` 时进行总结
+    pub fn new(token_budget: usize) -> Self {
+        Self {
+            summary: None,
+            turns: Vec::new(),
+            token_budget,
+        }
+    }
+
+    /// 交给模型的历史消息，摘要在最前面
+    pub fn messages(&self) -> Vec<ChatMessage> {
+        let summary = self
+            .summary
+            .as_ref()
+            .map(|summary| ChatMessage::system(format!("之前对话的摘要: {}", summary)));
+        summary
+            .into_iter()
+            .chain(self.turns.iter().flatten().cloned())
+            .collect()
+    }
+
+    /// 记录一条命令的完整对话
+    pub fn record_turn(&mut self, turn: Vec<ChatMessage>) {
+        if !turn.is_empty() {
+            self.turns.push(turn);
+        }
+    }
+
+    /// 清空对话历史和摘要
+    pub fn clear(&mut self) {
+        self.summary = None;
+        self.turns.clear();
+    }
+
+    /// 估计的 token 数
+    fn estimated_tokens(&self) -> usize {
+        let summary = self
+            .summary
+            .as_ref()
+            .map_or(0, |summary| estimate_text_tokens(summary) + MESSAGE_OVERHEAD_TOKENS);
+        summary + self.turns.iter().flatten().map(estimate_tokens).sum::<usize>()
+    }
+
+    /// 超出预算时把最近几轮以外的轮次总结为摘要。总结失败时丢弃这些轮次，
+    /// 仍然超出预算时继续丢弃最早的轮次，但至少保留最近一轮
+    pub async fn compact(&mut self, llm: Arc<dyn LanguageModel>) {
+        if self.estimated_tokens() <= self.token_budget {
+            return;
+        }
+
+        let split = self.turns.len().saturating_sub(KEEP_RECENT_TURNS);
+        if split > 0 {
+            let older: Vec<Vec<ChatMessage>> = self.turns.drain(..split).collect();
+            let transcript = transcript(self.summary.as_deref(), &older);
+            match summarize(llm, transcript).await {
+                Ok(summary) => {
+                    println!("对话历史超出预算，已将较早的 {} 轮对话总结为摘要", older.len());
+                    self.summary = Some(summary);
+                }
+                Err(e) => println!("总结对话历史失败，丢弃较早的 {} 轮对话: {}", older.len(), e),
+            }
+        }
+
+        while self.estimated_tokens() > self.token_budget && self.turns.len() > 1 {
+            self.turns.remove(0);
+        }
+    }
+}
+
+/// 按 3 个字节一个 token 粗略估计文本的 token 数，中文约一个汉字一个 token，英文偏多
+fn estimate_text_tokens(text: &str) -> usize {
+    text.len() / 3
+}
+
+fn estimate_tokens(message: &ChatMessage) -> usize {
+    let tool_calls: usize = message
+        .tool_calls
+        .iter()
+        .map(|call| estimate_text_tokens(&call.name) + estimate_text_tokens(&call.arguments))
+        .sum();
+    estimate_text_tokens(&message.content) + tool_calls + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// 把对话整理为供总结的文本
+fn transcript(summary: Option<&str>, turns: &[Vec<ChatMessage>]) -> String {
+    let mut text = String::new();
+    if let Some(summary) = summary {
+        text.push_str(&format!("更早的对话摘要: {}\n", summary));
+    }
+    for message in turns.iter().flatten() {
+        match message.role.as_str() {
+            "user" => text.push_str(&format!("用户: {}\n", message.content)),
+            "tool" => text.push_str(&format!("执行结果: {}\n", message.content)),
+            _ => {
+                for call in &message.tool_calls {
+                    text.push_str(&format!("助手调用 {}，参数: {}\n", call.name, call.arguments));
+                }
+                if !message.content.is_empty() {
+                    text.push_str(&format!("助手: {}\n", message.content));
+                }
+            }
+        }
+    }
+    text
+}
+
+/// 请 LLM 总结对话
+async fn summarize(llm: Arc<dyn LanguageModel>, transcript: String) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let request = ChatCompletionRequest {
+        model: "gpt-3.5-turbo".to_string(), // 可以配置为其他模型
+        messages: vec![
+            ChatMessage::system(
+                "请把用户与桌面应用助手之间的对话总结为不超过 200 字的摘要。
+保留用户的每个请求、执行过的命令及其参数、执行结果和应用状态的变化，
+以便之后理解\"再来一次\"、\"撤销\"等后续命令。只输出摘要。",
+            ),
+            ChatMessage::user(transcript),
+        ],
+        tools: Vec::new(),
+    };
+    let response = llm.chat_completions(request).await?;
+    let summary = response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content.trim().to_string())
+        .unwrap_or_default();
+    if summary.is_empty() {
+        return Err("LLM 没有返回摘要".into());
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_interface::{ChatChoice, ChatCompletionResponse};
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// 记录收到的总结请求，返回固定的摘要，`summary` 为 `None` 时总结失败
+    struct FakeModel {
+        summary: Option<&'static str>,
+        transcripts: Mutex<Vec<String>>,
+    }
+
+    impl FakeModel {
+        fn new(summary: Option<&'static str>) -> Arc<Self> {
+            Arc::new(Self {
+                summary,
+                transcripts: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl LanguageModel for FakeModel {
+        async fn chat_completions(
+            &self,
+            request: ChatCompletionRequest,
+        ) -> Result<ChatCompletionResponse, Box<dyn Error + Send + Sync>> {
+            let transcript = request.messages.last().unwrap().content.clone();
+            self.transcripts.lock().unwrap().push(transcript);
+            let summary = self.summary.ok_or("模型不可用")?;
+            Ok(ChatCompletionResponse {
+                choices: vec![ChatChoice {
+                    message: ChatMessage {
+                        role: "assistant".to_string(),
+                        content: summary.to_string(),
+                        tool_calls: Vec::new(),
+                        tool_call_id: None,
+                    },
+                }],
+            })
+        }
+    }
+
+    /// 一轮约 17 个 token 的对话
+    fn turn(n: usize) -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::user(format!("第 {} 条命令: 换颜色", n)),
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: "好的".to_string(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+            },
+        ]
+    }
+
+    fn memory_with_turns(token_budget: usize, count: usize) -> ConversationMemory {
+        let mut memory = ConversationMemory::new(token_budget);
+        for n in 1..=count {
+            memory.record_turn(turn(n));
+        }
+        memory
+    }
+
+    fn user_messages(memory: &ConversationMemory) -> Vec<String> {
+        memory
+            .messages()
+            .into_iter()
+            .filter(|message| message.role == "user")
+            .map(|message| message.content)
+            .collect()
+    }
+
+    #[test]
+    fn turns_are_recorded_and_cleared() {
+        let mut memory = memory_with_turns(1000, 2);
+        memory.record_turn(Vec::new());
+        assert_eq!(memory.messages().len(), 4);
+
+        memory.summary = Some("之前的摘要".to_string());
+        memory.clear();
+        assert!(memory.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_within_budget_is_kept() {
+        let llm = FakeModel::new(Some("摘要"));
+        let mut memory = memory_with_turns(1000, 4);
+        memory.compact(llm.clone()).await;
+
+        assert!(llm.transcripts.lock().unwrap().is_empty());
+        assert_eq!(user_messages(&memory).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn older_turns_are_summarized() {
+        let llm = FakeModel::new(Some("用户把颜色换了两次"));
+        let mut memory = memory_with_turns(60, 4);
+        assert!(memory.estimated_tokens() > 60);
+        memory.compact(llm.clone()).await;
+
+        // 只有最近两轮之前的轮次交给模型总结
+        let transcripts = llm.transcripts.lock().unwrap();
+        assert_eq!(transcripts.len(), 1);
+        assert!(transcripts[0].contains("第 1 条命令") && transcripts[0].contains("第 2 条命令"));
+        assert!(!transcripts[0].contains("第 3 条命令"));
+
+        let messages = memory.messages();
+        assert_eq!(messages[0].role, "system");
+        assert!(messages[0].content.contains("用户把颜色换了两次"));
+        assert_eq!(
+            user_messages(&memory),
+            vec!["第 3 条命令: 换颜色", "第 4 条命令: 换颜色"]
+        );
+        assert!(memory.estimated_tokens() <= 60);
+    }
+
+    #[tokio::test]
+    async fn earlier_summary_is_included_in_the_next_summary() {
+        let llm = FakeModel::new(Some("新的摘要"));
+        let mut memory = memory_with_turns(60, 4);
+        memory.summary = Some("更早的摘要".to_string());
+        memory.compact(llm.clone()).await;
+
+        assert!(llm.transcripts.lock().unwrap()[0].contains("更早的摘要"));
+        assert!(memory.messages()[0].content.contains("新的摘要"));
+    }
+
+    #[tokio::test]
+    async fn failed_summary_drops_turns_but_keeps_the_latest() {
+        let llm = FakeModel::new(None);
+        let mut memory = memory_with_turns(20, 4);
+        memory.compact(llm.clone()).await;
+
+        assert_eq!(llm.transcripts.lock().unwrap().len(), 1);
+        assert!(memory.messages().iter().all(|message| message.role != "system"));
+        assert_eq!(user_messages(&memory), vec!["第 4 条命令: 换颜色"]);
+
+        // 单独一轮超出预算时也保留
+        let mut memory = memory_with_turns(1, 1);
+        memory.compact(llm).await;
+        assert_eq!(user_messages(&memory).len(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+//! 把应用的命令和元素操作作为工具提供给模型，并把模型的工具调用转换为 ACP 请求
+
+use crate::llm_interface::{ToolCall, ToolDefinition};
+use acp::{CommandInfo, RequestPayload};
+use serde_json::{json, Map, Value};
+
+/// 可以作为工具调用的元素操作，工具名称与 ACP 的 `action` 相同
+const ELEMENT_ACTIONS: [&str; 5] = ["click", "focus", "set_value", "toggle", "scroll"];
+
+/// 元素查询语法的说明，见 `acp::query`
+const QUERY_DESCRIPTION: &str = "不确定元素 ID 时使用的元素查询，例如 role=button name~\"颜色\"。\
+查询由空格分隔的条件组成，字段有 id、role、label、text、name（匹配 label 或 text）和 path（子元素下标，如 path=0/1），\
+'=' 表示完全相同，'~' 表示包含（不区分大小写）";
+
+/// 应用的命令和元素操作对应的工具。命令与元素操作同名时只提供命令，
+/// 与 `payload_from_tool_call` 的查找顺序一致
+pub fn build_tools(commands: &[CommandInfo]) -> Vec<ToolDefinition> {
+    let mut tools: Vec<ToolDefinition> = commands.iter().map(command_tool).collect();
+    tools.extend(
+        ELEMENT_ACTIONS
+            .iter()
+            .filter(|action| !commands.iter().any(|command| command.name == **action))
+            .map(|action| element_tool(action)),
+    );
+    tools
+}
+
+/// 命令对应的工具，应用没有提供参数 Schema 时视为没有参数
+fn command_tool(command: &CommandInfo) -> ToolDefinition {
+    let parameters = match &command.params_schema {
+        Value::Object(schema) if schema.contains_key("type") => command.params_schema.clone(),
+        _ => json!({ "type": "object", "properties": {} }),
+    };
+    let description = if command.description.is_empty() {
+        format!("执行应用命令 {}", command.name)
+    } else {
+        command.description.clone()
+    };
+    ToolDefinition {
+        name: command.name.clone(),
+        description,
+        parameters,
+    }
+}
+
+/// 元素操作对应的工具，参数与 ACP 请求的字段相同
+fn element_tool(action: &str) -> ToolDefinition {
+    let mut properties = Map::new();
+    properties.insert(
+        "element_id".to_string(),
+        json!({ "type": "string", "description": "元素 ID，必须来自界面元素树" }),
+    );
+    properties.insert(
+        "target_query".to_string(),
+        json!({ "type": "string", "description": QUERY_DESCRIPTION }),
+    );
+    let mut required = Vec::new();
+
+    let description = match action {
+        "click" => "点击元素",
+        "focus" => "让元素获得焦点",
+        "toggle" => "切换元素的选中状态，例如复选框",
+        "set_value" => {
+            properties.insert(
+                "value".to_string(),
+                json!({ "type": "string", "description": "新的值" }),
+            );
+            required.push("value");
+            "设置元素的值，例如输入框的文本"
+        }
+        "scroll" => {
+            properties.insert(
+                "dx".to_string(),
+                json!({ "type": "number", "description": "水平滚动的逻辑像素，默认 0" }),
+            );
+            properties.insert(
+                "dy".to_string(),
+                json!({ "type": "number", "description": "垂直滚动的逻辑像素，默认 0" }),
+            );
+            "滚动元素"
+        }
+        _ => unreachable!("未知的元素操作: {}", action),
+    };
+
+    ToolDefinition {
+        name: action.to_string(),
+        description: format!(
+            "{}。element_id 和 target_query 必须恰好提供一个，操作必须在该元素的 actions 列表中",
+            description
+        ),
+        parameters: json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        }),
+    }
+}
+
+/// 把一次工具调用转换为 ACP 请求，工具不存在或参数不合法时返回错误说明
+pub fn payload_from_tool_call(call: &ToolCall, commands: &[CommandInfo]) -> Result<RequestPayload, String> {
+    // 没有参数的工具调用，部分模型会给出空字符串
+    let arguments = if call.arguments.trim().is_empty() {
+        Value::Object(Map::new())
+    } else {
+        serde_json::from_str(&call.arguments)
+            .map_err(|e| format!("工具 {} 的参数不是合法的 JSON: {}", call.name, e))?
+    };
+    let Value::Object(mut arguments) = arguments else {
+        return Err(format!("工具 {} 的参数必须是 JSON 对象", call.name));
+    };
+
+    if commands.iter().any(|command| command.name == call.name) {
+        return Ok(RequestPayload::CustomCommand {
+            command_name: call.name.clone(),
+            params: (!arguments.is_empty()).then_some(Value::Object(arguments)),
+        });
+    }
+    if !ELEMENT_ACTIONS.contains(&call.name.as_str()) {
+        return Err(format!("没有名为 {} 的工具", call.name));
+    }
+
+    // 元素操作的参数就是 ACP 请求的字段
+    arguments.insert("action".to_string(), Value::String(call.name.clone()));
+    serde_json::from_value(Value::Object(arguments))
+        .map_err(|e| format!("工具 {} 的参数不合法: {}", call.name, e))
+}
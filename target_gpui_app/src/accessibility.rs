@@ -0,0 +1,362 @@
+use crate::{AppState, CancellationToken, ElementAction, ElementError};
+use acp::{
+    accesskit::{Action, ActionData, ActionRequest, Node, NodeId, Rect, Role, Tree, TreeUpdate},
+    UiElement,
+};
+use serde_json::Value;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// AccessKit 滚动操作每次滚动的距离，单位为逻辑像素
+const SCROLL_STEP: f32 = 40.0;
+
+/// 根据元素 ID 计算 AccessKit 节点 ID，同一进程内保持稳定
+pub fn node_id(element_id: &str) -> NodeId {
+    let mut hasher = DefaultHasher::new();
+    element_id.hash(&mut hasher);
+    NodeId(hasher.finish())
+}
+
+/// 将元素角色映射为 AccessKit 角色
+fn role(element: &UiElement) -> Role {
+    match element.role.as_str() {
+        "window" => Role::Window,
+        "button" => Role::Button,
+        "label" => Role::Label,
+        "checkbox" => Role::CheckBox,
+        "text_input" => Role::TextInput,
+        "slider" => Role::Slider,
+        "list" => Role::List,
+        _ => Role::GenericContainer,
+    }
+}
+
+/// 将元素支持的操作映射为 AccessKit 操作
+fn actions(element: &UiElement) -> Vec<Action> {
+    let mut actions = Vec::new();
+    for action in &element.actions {
+        match action.as_str() {
+            "click" | "toggle" => actions.push(Action::Click),
+            "focus" => actions.push(Action::Focus),
+            "set_value" => actions.push(Action::SetValue),
+            "scroll" => actions.extend([Action::ScrollUp, Action::ScrollDown]),
+            _ => {}
+        }
+    }
+    actions.dedup();
+    actions
+}
+
+/// 生成与元素树对应的完整 AccessKit 树，
+/// 焦点为 `focused` 指定的元素，没有元素获得焦点或该元素不在树中时为根节点。
+/// 不另外维护一棵增量更新的树：元素树在每个请求之前按应用状态重建，
+/// 每次从它完整生成的树总是与界面一致
+pub fn tree_update(root: &UiElement, focused: Option<&str>) -> TreeUpdate {
+    let mut nodes = Vec::new();
+    push_nodes(root, &mut nodes);
+    let root_id = node_id(&root.id);
+    let focus = focused
+        .filter(|id| root.find(id).is_some())
+        .map_or(root_id, node_id);
+
+    TreeUpdate {
+        nodes,
+        tree: Some(Tree {
+            root: root_id,
+            toolkit_name: Some("GPUI".to_string()),
+            toolkit_version: None,
+        }),
+        focus,
+    }
+}
+
+fn push_nodes(element: &UiElement, nodes: &mut Vec<(NodeId, Node)>) {
+    let mut node = Node::new(role(element));
+    node.set_author_id(element.id.as_str());
+    if let Some(label) = &element.label {
+        node.set_label(label.as_str());
+    }
+    if let Some(text) = &element.text {
+        node.set_value(text.as_str());
+    }
+    if !element.enabled {
+        node.set_disabled();
+    }
+    if !element.visible {
+        node.set_hidden();
+    }
+    if let Some(bounds) = &element.bounds {
+        node.set_bounds(Rect {
+            x0: bounds.x as f64,
+            y0: bounds.y as f64,
+            x1: (bounds.x + bounds.width) as f64,
+            y1: (bounds.y + bounds.height) as f64,
+        });
+    }
+    for action in actions(element) {
+        node.add_action(action);
+    }
+    node.set_children(
+        element
+            .children
+            .iter()
+            .map(|child| node_id(&child.id))
+            .collect::<Vec<_>>(),
+    );
+
+    nodes.push((node_id(&element.id), node));
+    for child in &element.children {
+        push_nodes(child, nodes);
+    }
+}
+
+/// 查找节点 ID 对应的元素
+fn find_by_node_id(element: &UiElement, target: NodeId) -> Option<&UiElement> {
+    if node_id(&element.id) == target {
+        return Some(element);
+    }
+    element
+        .children
+        .iter()
+        .find_map(|child| find_by_node_id(child, target))
+}
+
+/// 执行 AccessKit 操作请求，交给元素注册表中与界面事件相同的处理函数
+pub fn perform_action_request(
+    request: &ActionRequest,
+    app_state: &AppState,
+    token: &CancellationToken,
+) -> Result<String, ElementError> {
+    let root = app_state
+        .elements()
+        .snapshot()
+        .ok_or(ElementError::NotRendered)?;
+    let element = find_by_node_id(&root, request.target)
+        .ok_or_else(|| ElementError::UnknownElement(format!("{:?}", request.target)))?;
+    let supports = |name: &str| element.actions.iter().any(|a| a == name);
+
+    let action = match (request.action, &request.data) {
+        // AccessKit 用 Click 表示默认操作，复选框等元素对应 toggle
+        (Action::Click, _) if !supports("click") && supports("toggle") => ElementAction::Toggle,
+        (Action::Click, _) => ElementAction::Click,
+        (Action::Focus, _) => ElementAction::Focus,
+        (Action::SetValue, Some(ActionData::Value(value))) => {
+            ElementAction::SetValue(Value::String(value.to_string()))
+        }
+        (Action::SetValue, Some(ActionData::NumericValue(value))) => {
+            ElementAction::SetValue(Value::from(*value))
+        }
+        (Action::ScrollUp, _) => ElementAction::Scroll {
+            dx: 0.0,
+            dy: -SCROLL_STEP,
+        },
+        (Action::ScrollDown, _) => ElementAction::Scroll {
+            dx: 0.0,
+            dy: SCROLL_STEP,
+        },
+        (action, _) => {
+            return Err(ElementError::UnsupportedAction {
+                element_id: element.id.clone(),
+                action: format!("{:?}", action),
+            })
+        }
+    };
+
+    app_state.elements().perform(&element.id, action, app_state, token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use acp::ElementBounds;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn tree() -> UiElement {
+        let mut label = UiElement::new("label", "label").with_label("当前背景").with_text("White");
+        label.enabled = false;
+        let mut hidden = UiElement::new("hidden", "panel");
+        hidden.visible = false;
+        let mut button = UiElement::new("button", "button")
+            .with_label("循环背景颜色")
+            .with_actions(&["click", "focus"]);
+        button.bounds = Some(ElementBounds {
+            x: 10.0,
+            y: 20.0,
+            width: 100.0,
+            height: 30.0,
+        });
+
+        UiElement::new("root", "window").with_label("窗口").with_children(vec![
+            label,
+            button,
+            UiElement::new("checkbox", "checkbox").with_actions(&["toggle"]),
+            UiElement::new("slider", "slider").with_actions(&["set_value", "scroll"]),
+            hidden,
+        ])
+    }
+
+    fn node<'a>(update: &'a TreeUpdate, element_id: &str) -> &'a Node {
+        let id = node_id(element_id);
+        &update.nodes.iter().find(|(node_id, _)| *node_id == id).unwrap().1
+    }
+
+    #[test]
+    fn tree_update_mirrors_the_elements() {
+        let update = tree_update(&tree(), None);
+        assert_eq!(update.nodes.len(), 6);
+        assert_eq!(update.tree.as_ref().unwrap().root, node_id("root"));
+        assert_eq!(update.focus, node_id("root"));
+
+        let root = node(&update, "root");
+        assert_eq!(root.role(), Role::Window);
+        assert_eq!(root.label(), Some("窗口"));
+        let children = ["label", "button", "checkbox", "slider", "hidden"].map(node_id);
+        assert_eq!(root.children(), children);
+
+        let label = node(&update, "label");
+        assert_eq!(label.role(), Role::Label);
+        assert_eq!(label.value(), Some("White"));
+        assert_eq!(label.author_id(), Some("label"));
+        assert!(label.is_disabled());
+        assert!(node(&update, "hidden").is_hidden());
+        assert_eq!(node(&update, "hidden").role(), Role::GenericContainer);
+
+        let button = node(&update, "button");
+        assert_eq!(button.role(), Role::Button);
+        assert!(button.supports_action(Action::Click) && button.supports_action(Action::Focus));
+        assert!(!button.supports_action(Action::SetValue));
+        let bounds = button.bounds().unwrap();
+        assert_eq!((bounds.x0, bounds.y0, bounds.x1, bounds.y1), (10.0, 20.0, 110.0, 50.0));
+
+        let checkbox = node(&update, "checkbox");
+        assert_eq!(checkbox.role(), Role::CheckBox);
+        assert!(checkbox.supports_action(Action::Click));
+
+        let slider = node(&update, "slider");
+        assert_eq!(slider.role(), Role::Slider);
+        for action in [Action::SetValue, Action::ScrollUp, Action::ScrollDown] {
+            assert!(slider.supports_action(action), "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn focus_follows_the_focused_element() {
+        assert_eq!(tree_update(&tree(), Some("button")).focus, node_id("button"));
+        // 不在树中的元素无法获得焦点
+        assert_eq!(tree_update(&tree(), Some("gone")).focus, node_id("root"));
+    }
+
+    #[test]
+    fn node_ids_round_trip() {
+        let root = tree();
+        assert_eq!(node_id("button"), node_id("button"));
+        assert_ne!(node_id("button"), node_id("checkbox"));
+        for id in ["root", "label", "button", "checkbox", "slider", "hidden"] {
+            assert_eq!(find_by_node_id(&root, node_id(id)).unwrap().id, id);
+        }
+        assert!(find_by_node_id(&root, node_id("gone")).is_none());
+    }
+
+    /// 处理函数收到的元素 ID 和操作
+    type Performed = Arc<Mutex<Vec<(String, ElementAction)>>>;
+
+    /// 注册记录收到的操作的处理函数，返回记录
+    fn recording_app_state() -> (AppState, Performed) {
+        let app_state = AppState::new();
+        let performed = Arc::new(Mutex::new(Vec::new()));
+        for (id, actions) in [
+            ("button", &["click", "focus"][..]),
+            ("checkbox", &["toggle"][..]),
+            ("slider", &["set_value", "scroll"][..]),
+        ] {
+            for &action in actions {
+                let performed = performed.clone();
+                app_state.elements().register_handler(id, action, move |_, action, _| {
+                    performed.lock().unwrap().push((id.to_string(), action.clone()));
+                    Ok(action.name().to_string())
+                });
+            }
+        }
+        (app_state, performed)
+    }
+
+    fn request(element_id: &str, action: Action, data: Option<ActionData>) -> ActionRequest {
+        ActionRequest {
+            action,
+            target: node_id(element_id),
+            data,
+        }
+    }
+
+    #[test]
+    fn action_requests_reach_the_element_handlers() {
+        let (app_state, performed) = recording_app_state();
+        let token = CancellationToken::new();
+        let perform = |request: ActionRequest| perform_action_request(&request, &app_state, &token);
+
+        assert!(matches!(
+            perform(request("button", Action::Click, None)),
+            Err(ElementError::NotRendered)
+        ));
+        app_state.elements().update_tree(tree(), &app_state);
+
+        let cases = [
+            (request("button", Action::Click, None), "button", ElementAction::Click),
+            (request("button", Action::Focus, None), "button", ElementAction::Focus),
+            // 只支持 toggle 的元素把默认操作当作 toggle
+            (request("checkbox", Action::Click, None), "checkbox", ElementAction::Toggle),
+            (
+                request("slider", Action::SetValue, Some(ActionData::Value("42".into()))),
+                "slider",
+                ElementAction::SetValue(json!("42")),
+            ),
+            (
+                request("slider", Action::SetValue, Some(ActionData::NumericValue(42.5))),
+                "slider",
+                ElementAction::SetValue(json!(42.5)),
+            ),
+            (
+                request("slider", Action::ScrollUp, None),
+                "slider",
+                ElementAction::Scroll { dx: 0.0, dy: -SCROLL_STEP },
+            ),
+            (
+                request("slider", Action::ScrollDown, None),
+                "slider",
+                ElementAction::Scroll { dx: 0.0, dy: SCROLL_STEP },
+            ),
+        ];
+        for (request, element_id, action) in cases {
+            perform(request.clone()).unwrap();
+            let last = performed.lock().unwrap().pop();
+            assert_eq!(last, Some((element_id.to_string(), action)), "{:?}", request);
+        }
+
+        assert!(matches!(
+            perform(request("button", Action::Expand, None)),
+            Err(ElementError::UnsupportedAction { .. })
+        ));
+        // 没有附带值的 SetValue 无法执行
+        assert!(matches!(
+            perform(request("slider", Action::SetValue, None)),
+            Err(ElementError::UnsupportedAction { .. })
+        ));
+        // 元素没有注册的操作交给注册表拒绝
+        assert!(matches!(
+            perform(request("hidden", Action::Click, None)),
+            Err(ElementError::UnsupportedAction { .. })
+        ));
+        assert!(matches!(
+            perform(request("label", Action::Click, None)),
+            Err(ElementError::Disabled(_))
+        ));
+        assert!(matches!(
+            perform(request("gone", Action::Click, None)),
+            Err(ElementError::UnknownElement(_))
+        ));
+        assert!(performed.lock().unwrap().is_empty());
+    }
+}
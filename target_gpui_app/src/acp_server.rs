@@ -0,0 +1,1327 @@
+use crate::{
+    accessibility,
+    transport::{AcpListener, AcpStream},
+    websocket::handle_ws_connection,
+    AppState, CancellationToken, CommandError, ElementAction, ElementError, SUPPORTED_EVENTS,
+};
+use acp::{
+    discovery::AppEntry, query::QueryError, AcpError, AcpMessage, CommandInfo, ElementTarget, ErrorCode,
+    RequestPayload, ResponseData, ResponsePayload, UiElement, Welcome, DEFAULT_TCP_ADDR,
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// 监听线程检查关闭标志的间隔
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 单条消息（含换行符）的最大字节数
+pub(crate) const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+/// 服务器支持的 `action` 列表，在握手时告知客户端
+const SUPPORTED_ACTIONS: &[&str] = &[
+    "hello",
+    "custom_command",
+    "list_commands",
+    "get_ui_tree",
+    "click",
+    "focus",
+    "set_value",
+    "toggle",
+    "scroll",
+    "get_accessibility_tree",
+    "accessibility_action",
+    "subscribe",
+    "unsubscribe",
+    "cancel",
+    "ping",
+    "batch",
+];
+
+/// ACP 服务器配置
+#[derive(Debug, Clone)]
+pub struct AcpServerConfig {
+    /// TCP 监听地址，为 `None` 时不监听 TCP
+    pub addr: Option<String>,
+    /// Unix 套接字路径，为 `None` 时不监听 Unix 套接字
+    pub unix_socket: Option<PathBuf>,
+    /// WebSocket 监听地址，为 `None` 时不监听 WebSocket
+    pub ws_addr: Option<String>,
+    /// 允许发起 WebSocket 连接的网页来源，例如 `http://localhost:3000`。
+    /// 握手请求带有其他 `Origin` 时被拒绝，不带 `Origin` 的客户端总是允许
+    pub ws_allowed_origins: Vec<String>,
+    /// 发现目录，启动后在其中写入本实例实际监听的地址，为 `None` 时不写入
+    pub discovery_dir: Option<PathBuf>,
+    /// 客户端必须在握手中出示的令牌，为 `None` 时不要求认证。
+    /// 设置了发现目录时令牌会写入其中只有当前用户可以读取的文件，写入失败时服务器启动失败
+    pub auth_token: Option<String>,
+    /// 最大并发连接数，超出的连接会收到错误响应后被关闭
+    pub max_connections: usize,
+    /// 连接空闲超时，超过该时间没有收到请求的连接会被关闭
+    pub idle_timeout: Duration,
+}
+
+impl Default for AcpServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: Some(DEFAULT_TCP_ADDR.to_string()),
+            unix_socket: None,
+            ws_addr: None,
+            ws_allowed_origins: Vec::new(),
+            discovery_dir: None,
+            auth_token: None,
+            max_connections: 16,
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// 活动连接表，用于关闭服务器时断开所有连接
+type Connections = Arc<Mutex<HashMap<u64, AcpStream>>>;
+
+/// ACP 服务器，每个监听地址和每个连接都由独立线程处理
+pub struct AcpServer {
+    local_addr: Option<SocketAddr>,
+    ws_addr: Option<SocketAddr>,
+    unix_socket: Option<PathBuf>,
+    /// 本实例的发现文件和令牌文件，关闭时删除
+    discovery_files: Vec<PathBuf>,
+    shutdown: Arc<AtomicBool>,
+    connections: Connections,
+    accept_threads: Vec<JoinHandle<()>>,
+}
+
+impl AcpServer {
+    /// 绑定地址并在后台线程中开始接受连接
+    pub fn start(config: AcpServerConfig, app_state: Arc<AppState>) -> io::Result<Self> {
+        let mut listeners = Vec::new();
+        let mut local_addr = None;
+        if let Some(addr) = &config.addr {
+            let listener = AcpListener::bind_tcp(addr)?;
+            if let AcpListener::Tcp(tcp) = &listener {
+                local_addr = Some(tcp.local_addr()?);
+            }
+            listeners.push(listener);
+        }
+        let mut ws_addr = None;
+        if let Some(addr) = &config.ws_addr {
+            let listener = AcpListener::bind_ws(addr)?;
+            if let AcpListener::WebSocket(tcp) = &listener {
+                ws_addr = Some(tcp.local_addr()?);
+            }
+            listeners.push(listener);
+        }
+        if let Some(path) = &config.unix_socket {
+            listeners.push(AcpListener::bind_unix(path)?);
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "没有配置 ACP 监听地址"));
+        }
+
+        // 在开始接受连接前写入发现文件，令牌写入失败时不启动服务器
+        let mut addresses: Vec<String> = local_addr.iter().map(|addr| addr.to_string()).collect();
+        addresses.extend(ws_addr.iter().map(|addr| format!("ws://{}/", addr)));
+        addresses.extend(config.unix_socket.iter().map(|path| format!("unix:{}", path.display())));
+        let discovery_files = match &config.discovery_dir {
+            Some(dir) => write_discovery_files(dir, addresses, config.auth_token.as_deref())?,
+            None => Vec::new(),
+        };
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let next_connection_id = Arc::new(AtomicU64::new(0));
+
+        let accept_threads = listeners
+            .into_iter()
+            .map(|listener| {
+                println!("ACP 服务器已启动在 {}", listener.describe());
+                let config = config.clone();
+                let app_state = app_state.clone();
+                let shutdown = shutdown.clone();
+                let connections = connections.clone();
+                let next_connection_id = next_connection_id.clone();
+                thread::spawn(move || {
+                    accept_loop(listener, config, app_state, shutdown, connections, next_connection_id)
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            local_addr,
+            ws_addr,
+            unix_socket: config.unix_socket,
+            discovery_files,
+            shutdown,
+            connections,
+            accept_threads,
+        })
+    }
+
+    /// 服务器实际监听的 TCP 地址，未监听 TCP 时为 `None`
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// 服务器实际监听的 WebSocket 地址，未监听 WebSocket 时为 `None`
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    /// 服务器监听的 Unix 套接字路径
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    /// 停止接受新连接，并断开所有活动连接
+    pub fn shutdown(&mut self) {
+        if self.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        for stream in self.connections.lock().unwrap().values() {
+            let _ = stream.shutdown();
+        }
+
+        for path in self.discovery_files.drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+
+        // 监听线程退出时释放监听器，Unix 套接字文件随之删除
+        for accept_thread in self.accept_threads.drain(..) {
+            let _ = accept_thread.join();
+        }
+
+        println!("ACP 服务器已关闭");
+    }
+}
+
+impl Drop for AcpServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// 在发现目录中记录本实例的地址和认证令牌，返回写入的文件。
+/// 令牌写入失败时返回错误，否则客户端无法得到令牌，服务器也就无法使用；
+/// 发现文件写入失败时只打印警告，客户端仍可以通过已知地址连接
+fn write_discovery_files(
+    dir: &Path,
+    addresses: Vec<String>,
+    auth_token: Option<&str>,
+) -> io::Result<Vec<PathBuf>> {
+    let entry = AppEntry {
+        app_name: env!("CARGO_PKG_NAME").to_string(),
+        pid: std::process::id(),
+        addresses,
+    };
+    let mut files = Vec::new();
+
+    // 先写令牌，客户端看到发现文件时总能读到令牌
+    if let Some(token) = auth_token {
+        let path = entry
+            .write_token(dir, token)
+            .map_err(|e| io::Error::new(e.kind(), format!("无法写入认证令牌文件: {}", e)))?;
+        files.push(path);
+    }
+    match entry.write(dir) {
+        Ok(path) => {
+            println!("ACP 地址已写入发现文件: {}", path.display());
+            files.push(path);
+        }
+        Err(e) => eprintln!("警告: 无法写入发现文件: {}", e),
+    }
+    Ok(files)
+}
+
+/// 接受连接，为每个连接启动一个处理线程
+fn accept_loop(
+    listener: AcpListener,
+    config: AcpServerConfig,
+    app_state: Arc<AppState>,
+    shutdown: Arc<AtomicBool>,
+    connections: Connections,
+    next_connection_id: Arc<AtomicU64>,
+) {
+    let websocket = listener.is_websocket();
+    while !shutdown.load(Ordering::SeqCst) {
+        let (mut stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("接受连接时出错: {}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = stream.set_read_timeout(Some(config.idle_timeout)) {
+            eprintln!("配置 ACP 连接时出错: {}", e);
+            continue;
+        }
+
+        let connection_id = next_connection_id.fetch_add(1, Ordering::SeqCst);
+
+        {
+            let mut active = connections.lock().unwrap();
+            if active.len() >= config.max_connections {
+                eprintln!("ACP 连接数已达上限 ({})，拒绝来自 {} 的连接", config.max_connections, peer_addr);
+                // WebSocket 连接在握手前拒绝，客户端只会看到连接被关闭
+                if !websocket {
+                    send_error_response(&mut stream, 0, ErrorCode::ServerBusy, "连接数已达上限");
+                }
+                continue;
+            }
+            match stream.try_clone() {
+                Ok(clone) => {
+                    active.insert(connection_id, clone);
+                }
+                Err(e) => {
+                    eprintln!("无法克隆 ACP 连接: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        println!("新的 ACP 连接已建立: {}", peer_addr);
+
+        let app_state = app_state.clone();
+        let connections = connections.clone();
+        let idle_timeout = config.idle_timeout;
+        let auth_token = config.auth_token.clone();
+        let allowed_origins = config.ws_allowed_origins.clone();
+        thread::spawn(move || {
+            let session = Session::new(connection_id, auth_token);
+            if websocket {
+                handle_ws_connection(stream, session, app_state, idle_timeout, &allowed_origins);
+            } else {
+                handle_acp_connection(stream, session, app_state);
+            }
+            connections.lock().unwrap().remove(&connection_id);
+        });
+    }
+}
+
+/// 连接的会话状态
+pub struct Session {
+    connection_id: u64,
+    /// 握手时必须出示的令牌，为 `None` 时不要求认证
+    auth_token: Option<String>,
+    authenticated: bool,
+}
+
+impl Session {
+    pub fn new(connection_id: u64, auth_token: Option<String>) -> Self {
+        Self {
+            connection_id,
+            authenticated: auth_token.is_none(),
+            auth_token,
+        }
+    }
+
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// 检查请求能否执行。未认证的连接只能发送 `hello`，令牌正确时连接通过认证
+    fn authorize(&mut self, payload: &RequestPayload) -> Result<(), AcpError> {
+        if self.authenticated {
+            return Ok(());
+        }
+        let Some(expected) = &self.auth_token else {
+            return Ok(());
+        };
+
+        match payload {
+            RequestPayload::Hello {
+                token: Some(token), ..
+            } if tokens_match(expected, token) => {
+                self.authenticated = true;
+                Ok(())
+            }
+            RequestPayload::Hello { token: Some(_), .. } => {
+                eprintln!("ACP 连接 {} 出示的认证令牌不正确", self.connection_id);
+                Err(AcpError::new(ErrorCode::Unauthorized, "认证令牌不正确"))
+            }
+            RequestPayload::Hello { token: None, .. } => Err(AcpError::new(
+                ErrorCode::Unauthorized,
+                "应用要求认证，请在 hello 中提供令牌",
+            )),
+            _ => Err(AcpError::new(
+                ErrorCode::Unauthorized,
+                "连接尚未认证，请先发送带有令牌的 hello",
+            )),
+        }
+    }
+}
+
+/// 比较令牌，不在第一个不同的字节处提前返回，避免通过响应时间逐字节猜出令牌
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 处理 ACP 连接，逐行读取请求直到客户端关闭连接。
+/// 响应和订阅的事件都交给写线程按顺序发送，每条消息都会得到响应，
+/// 无法找回 `seq_id` 的消息以 `seq_id` 0 回复
+pub fn handle_acp_connection(stream: AcpStream, session: Session, app_state: Arc<AppState>) {
+    // 文件描述符耗尽时无法克隆，只能关闭这条连接
+    let (reader, writer) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(reader), Ok(writer)) => (reader, writer),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("无法克隆 ACP 连接，关闭连接: {}", e);
+            let _ = stream.shutdown();
+            return;
+        }
+    };
+    let mut reader = BufReader::new(reader);
+    let (sender, receiver) = mpsc::channel();
+    let writer_thread = thread::spawn(move || write_loop(writer, receiver));
+    let mut dispatcher = Dispatcher::new(session, sender.clone(), app_state);
+
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        // 限制单条消息的长度，避免出错的客户端耗尽内存
+        match reader
+            .by_ref()
+            .take(MAX_MESSAGE_BYTES + 1)
+            .read_until(b'\n', &mut buffer)
+        {
+            // 读到 EOF，客户端已关闭连接
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                println!("ACP 连接空闲超时");
+                break;
+            }
+            Err(e) => {
+                eprintln!("读取 ACP 请求时出错: {}", e);
+                break;
+            }
+        }
+
+        if buffer.len() as u64 > MAX_MESSAGE_BYTES {
+            // 剩余部分无法可靠地分帧，回复后关闭连接
+            eprintln!("ACP 消息超过 {} 字节，关闭连接", MAX_MESSAGE_BYTES);
+            let _ = sender.send(error_message(
+                0,
+                ErrorCode::InvalidRequest,
+                format!("消息超过 {} 字节", MAX_MESSAGE_BYTES),
+            ));
+            break;
+        }
+
+        let line = match std::str::from_utf8(&buffer) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("ACP 消息不是有效的 UTF-8: {}", e);
+                if sender
+                    .send(error_message(0, ErrorCode::ParseError, "消息不是有效的 UTF-8".to_string()))
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        dispatcher.handle_message(line);
+    }
+
+    // 所有发送端释放后写线程退出
+    dispatcher.close();
+    drop(sender);
+    let _ = writer_thread.join();
+
+    let _ = stream.shutdown();
+    println!("ACP 连接已关闭");
+}
+
+/// 连接上仍在执行或排队的请求，按 `seq_id` 查找取消令牌
+type InFlight = Arc<Mutex<HashMap<u64, CancellationToken>>>;
+
+/// 排队等待工作线程执行的请求
+struct PendingRequest {
+    seq_id: u64,
+    payload: RequestPayload,
+    token: CancellationToken,
+}
+
+/// 连接的请求分发器。请求在工作线程中按到达顺序执行，读取线程因此可以随时处理 `cancel`；
+/// 设置了 `timeout_ms` 的请求由单独的线程在超时后回复。
+/// 执行完成、取消和超时中最先发生的一方发送响应，之后的结果被丢弃
+pub(crate) struct Dispatcher {
+    session: Session,
+    sender: Sender<AcpMessage>,
+    app_state: Arc<AppState>,
+    in_flight: InFlight,
+    queue: Option<Sender<PendingRequest>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    /// 启动连接的工作线程，响应通过 `sender` 发送
+    pub(crate) fn new(session: Session, sender: Sender<AcpMessage>, app_state: Arc<AppState>) -> Self {
+        let in_flight: InFlight = Arc::default();
+        let (queue, pending) = mpsc::channel();
+        let worker = {
+            let connection_id = session.connection_id;
+            let sender = sender.clone();
+            let app_state = app_state.clone();
+            let in_flight = in_flight.clone();
+            thread::spawn(move || work_loop(connection_id, pending, sender, app_state, in_flight))
+        };
+
+        Self {
+            session,
+            sender,
+            app_state,
+            in_flight,
+            queue: Some(queue),
+            worker: Some(worker),
+        }
+    }
+
+    /// 处理连接上收到的一条消息，空消息不回复。
+    /// 无法执行的消息、`cancel` 和 `ping` 在读取线程中直接回复，其他请求交给工作线程
+    pub(crate) fn handle_message(&mut self, text: &str) {
+        if text.trim().is_empty() {
+            return;
+        }
+
+        // 解析 ACP 消息，检查是否是请求类型
+        let message = AcpMessage::parse_line(text);
+        // 心跳定期发送，不打印
+        if !matches!(
+            message,
+            Ok(AcpMessage::Request {
+                payload: RequestPayload::Ping,
+                ..
+            })
+        ) {
+            println!("收到 ACP 请求: {}", text);
+        }
+        let (seq_id, timeout_ms, payload) = match message {
+            Ok(AcpMessage::Request {
+                seq_id,
+                timeout_ms,
+                payload,
+            }) => (seq_id, timeout_ms, payload),
+            Ok(other) => {
+                self.reply(
+                    other.seq_id(),
+                    ResponsePayload::error(ErrorCode::InvalidRequest, "不支持的消息类型"),
+                );
+                return;
+            }
+            Err(invalid) => {
+                eprintln!("解析 ACP 消息时出错: {}", invalid.error);
+                self.reply(invalid.seq_id.unwrap_or(0), ResponsePayload::failure(invalid.error));
+                return;
+            }
+        };
+
+        // 在读取线程中认证，握手之后紧接着发送的请求不必等待握手执行完毕
+        if let Err(error) = self.session.authorize(&payload) {
+            self.reply(seq_id, ResponsePayload::failure(error));
+            return;
+        }
+
+        match payload {
+            RequestPayload::Cancel { target_seq_id } => {
+                let response = self.cancel(target_seq_id);
+                self.reply(seq_id, response);
+                return;
+            }
+            // 心跳不排队，执行耗时的请求时连接仍然可以确认存活
+            RequestPayload::Ping => {
+                self.reply(seq_id, ResponsePayload::ok("pong"));
+                return;
+            }
+            _ => {}
+        }
+
+        let deadline = timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+        let token = CancellationToken::with_deadline(deadline);
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.contains_key(&seq_id) {
+                drop(in_flight);
+                self.reply(
+                    seq_id,
+                    ResponsePayload::failure(
+                        AcpError::new(
+                            ErrorCode::InvalidRequest,
+                            format!("seq_id 为 {} 的请求仍在执行", seq_id),
+                        )
+                        .with_details(json!({ "path": "seq_id" })),
+                    ),
+                );
+                return;
+            }
+            in_flight.insert(seq_id, token.clone());
+        }
+
+        if let Some(timeout_ms) = timeout_ms {
+            let token = token.clone();
+            let sender = self.sender.clone();
+            thread::spawn(move || {
+                token.wait_for_deadline();
+                if token.time_out() {
+                    eprintln!("ACP 请求 {} 超过 {} 毫秒仍未完成", seq_id, timeout_ms);
+                    let _ = sender.send(AcpMessage::Response {
+                        seq_id,
+                        payload: ResponsePayload::failure(
+                            AcpError::new(
+                                ErrorCode::TimedOut,
+                                format!("请求超过 {} 毫秒仍未完成", timeout_ms),
+                            )
+                            .with_details(json!({ "timeout_ms": timeout_ms })),
+                        ),
+                    });
+                }
+            });
+        }
+
+        if let Some(queue) = &self.queue {
+            let _ = queue.send(PendingRequest {
+                seq_id,
+                payload,
+                token,
+            });
+        }
+    }
+
+    /// 取消仍在执行或排队的请求，并代替它回复 `cancelled`
+    fn cancel(&self, target_seq_id: u64) -> ResponsePayload {
+        let token = self.in_flight.lock().unwrap().get(&target_seq_id).cloned();
+        match token {
+            Some(token) if token.cancel() => {
+                println!("ACP 请求 {} 已取消", target_seq_id);
+                self.reply(
+                    target_seq_id,
+                    ResponsePayload::error(ErrorCode::Cancelled, "请求已被客户端取消"),
+                );
+                ResponsePayload::ok(format!("已取消请求 {}", target_seq_id))
+            }
+            _ => ResponsePayload::failure(
+                AcpError::new(
+                    ErrorCode::InvalidParams,
+                    format!("请求 {} 不在执行中", target_seq_id),
+                )
+                .with_details(json!({ "path": "payload.target_seq_id" })),
+            ),
+        }
+    }
+
+    fn reply(&self, seq_id: u64, payload: ResponsePayload) {
+        let _ = self.sender.send(AcpMessage::Response { seq_id, payload });
+    }
+
+    /// 连接关闭时调用：取消未完成的请求，等待工作线程退出并移除连接的订阅
+    pub(crate) fn close(mut self) {
+        for token in self.in_flight.lock().unwrap().values() {
+            token.cancel();
+        }
+        // 队列关闭后工作线程执行完当前请求即退出
+        self.queue.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        self.app_state
+            .events()
+            .unsubscribe_connection(self.session.connection_id);
+    }
+}
+
+/// 工作线程，按顺序执行排队的请求。已被取消或超时的请求已经回复过，直接跳过。
+/// 正在执行的请求被取消或超时后，处理函数不会被中止，执行完毕前仍可能修改应用状态，
+/// 只是执行结果不再发送；原子批处理会在结束时回滚
+fn work_loop(
+    connection_id: u64,
+    pending: Receiver<PendingRequest>,
+    sender: Sender<AcpMessage>,
+    app_state: Arc<AppState>,
+    in_flight: InFlight,
+) {
+    for PendingRequest {
+        seq_id,
+        payload,
+        token,
+    } in pending
+    {
+        if !token.is_cancelled() {
+            let response = dispatch_request(connection_id, seq_id, payload, &sender, &app_state, &token);
+            if token.finish() {
+                let _ = sender.send(AcpMessage::Response {
+                    seq_id,
+                    payload: response,
+                });
+            }
+        }
+        in_flight.lock().unwrap().remove(&seq_id);
+    }
+}
+
+/// 处理连接上的一个请求，处理函数 panic 时也返回错误响应
+fn dispatch_request(
+    connection_id: u64,
+    seq_id: u64,
+    payload: RequestPayload,
+    sender: &Sender<AcpMessage>,
+    app_state: &AppState,
+    token: &CancellationToken,
+) -> ResponsePayload {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match payload {
+        // 订阅与连接绑定，在这里处理
+        RequestPayload::Subscribe { events } => {
+            handle_subscribe(connection_id, seq_id, events, sender, app_state)
+        }
+        RequestPayload::Unsubscribe { subscription_id } => {
+            if app_state.events().unsubscribe(connection_id, subscription_id) {
+                ResponsePayload::ok("已取消订阅")
+            } else {
+                ResponsePayload::failure(
+                    AcpError::new(
+                        ErrorCode::InvalidParams,
+                        format!("订阅不存在: {}", subscription_id),
+                    )
+                    .with_details(json!({ "path": "payload.subscription_id" })),
+                )
+            }
+        }
+        // 批处理自己持有事务锁
+        payload @ RequestPayload::Batch { .. } => handle_acp_request(payload, app_state, token),
+        payload => app_state.transaction(|| {
+            // 等待事务锁期间请求可能已被取消
+            if token.is_cancelled() {
+                return ResponsePayload::error(ErrorCode::Cancelled, "请求已取消");
+            }
+            handle_acp_request(payload, app_state, token)
+        }),
+    }));
+
+    result.unwrap_or_else(|_| {
+        eprintln!("处理 ACP 请求 {} 时发生 panic", seq_id);
+        ResponsePayload::error(ErrorCode::HandlerFailed, "处理请求时发生内部错误")
+    })
+}
+
+/// 生成错误响应消息
+pub(crate) fn error_message(seq_id: u64, code: ErrorCode, message: String) -> AcpMessage {
+    AcpMessage::Response {
+        seq_id,
+        payload: ResponsePayload::error(code, message),
+    }
+}
+
+/// 写线程，按顺序发送响应和事件
+fn write_loop(mut writer: AcpStream, receiver: Receiver<AcpMessage>) {
+    for message in receiver {
+        let line = match message.to_line() {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("序列化 ACP 消息时出错: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            eprintln!("发送 ACP 消息时出错: {}", e);
+            break;
+        }
+    }
+}
+
+/// 按顺序执行批处理中的请求。
+/// 非原子模式执行全部请求；原子模式在第一个失败处停止，并把应用状态回滚到批处理开始之前。
+/// 有请求失败时，响应的错误码取自第一个失败的请求，该请求的 `details` 放在 `details.details` 中。
+/// 批处理被取消或超时后不再执行剩余的请求，原子模式同样回滚
+fn handle_batch(
+    requests: Vec<RequestPayload>,
+    atomic: bool,
+    app_state: &AppState,
+    token: &CancellationToken,
+) -> ResponsePayload {
+    if let Some(index) = requests.iter().position(|request| !request.can_batch()) {
+        return ResponsePayload::failure(
+            AcpError::new(
+                ErrorCode::InvalidParams,
+                format!("批处理中不能包含 {} 请求", requests[index].action_name()),
+            )
+            .with_details(json!({ "path": format!("payload.requests[{}].action", index) })),
+        );
+    }
+
+    let total = requests.len();
+    app_state.transaction(|| {
+        let snapshot = app_state.snapshot();
+        let mut results = Vec::with_capacity(total);
+        let mut first_failure = None;
+
+        for (index, request) in requests.into_iter().enumerate() {
+            if token.is_cancelled() {
+                if atomic {
+                    app_state.restore(&snapshot);
+                }
+                return ResponsePayload::failure(
+                    AcpError::new(ErrorCode::Cancelled, format!("批处理在第 {} 项之前被取消", index + 1))
+                        .with_details(json!({ "index": index, "rolled_back": atomic })),
+                )
+                .with_data(ResponseData::Batch { results });
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| handle_acp_request(request, app_state, token)))
+                .unwrap_or_else(|_| {
+                    eprintln!("处理批处理第 {} 项时发生 panic", index + 1);
+                    ResponsePayload::error(ErrorCode::HandlerFailed, "处理请求时发生内部错误")
+                });
+            if !result.success && first_failure.is_none() {
+                first_failure = Some(match &result.error {
+                    Some(error) => (index, error.clone()),
+                    None => (index, AcpError::new(ErrorCode::HandlerFailed, result.message.clone())),
+                });
+            }
+            results.push(result);
+            if atomic && first_failure.is_some() {
+                break;
+            }
+        }
+
+        // 最后一项执行期间被取消或超时时，客户端已经收到 cancelled 或 timed_out，
+        // 原子模式同样回滚，不留下客户端不知道的修改
+        if atomic && token.is_cancelled() {
+            app_state.restore(&snapshot);
+            return ResponsePayload::failure(
+                AcpError::new(ErrorCode::Cancelled, "批处理执行期间被取消")
+                    .with_details(json!({ "index": results.len() - 1, "rolled_back": true })),
+            )
+            .with_data(ResponseData::Batch { results });
+        }
+
+        let Some((index, error)) = first_failure else {
+            return ResponsePayload::ok(format!("批处理完成，共 {} 项", total))
+                .with_data(ResponseData::Batch { results });
+        };
+
+        if atomic {
+            app_state.restore(&snapshot);
+        }
+        let message = if atomic {
+            format!("批处理第 {} 项失败，应用状态已回滚: {}", index + 1, error.message)
+        } else {
+            format!("批处理第 {} 项失败: {}", index + 1, error.message)
+        };
+        ResponsePayload::failure(AcpError::new(error.code, message).with_details(json!({
+            "index": index,
+            "rolled_back": atomic,
+            "details": error.details,
+        })))
+        .with_data(ResponseData::Batch { results })
+    })
+}
+
+/// 处理订阅请求，之后的事件以订阅请求的 `seq_id` 推送
+fn handle_subscribe(
+    connection_id: u64,
+    seq_id: u64,
+    events: Vec<String>,
+    sender: &Sender<AcpMessage>,
+    app_state: &AppState,
+) -> ResponsePayload {
+    let description = if events.is_empty() {
+        "全部事件".to_string()
+    } else {
+        events.join(", ")
+    };
+    match app_state
+        .events()
+        .subscribe(connection_id, seq_id, events, sender.clone())
+    {
+        Ok(()) => ResponsePayload::ok(format!("已订阅: {}", description)),
+        Err(error) => ResponsePayload::failure(error),
+    }
+}
+
+/// 处理单个 ACP 请求并返回响应载荷
+/// 处理函数可以通过 `token` 得知请求已被取消或超时
+pub fn handle_acp_request(
+    payload: RequestPayload,
+    app_state: &AppState,
+    token: &CancellationToken,
+) -> ResponsePayload {
+    match payload {
+        RequestPayload::Batch { requests, atomic } => handle_batch(requests, atomic, app_state, token),
+        RequestPayload::Hello {
+            protocol_version,
+            client_name,
+            client_version,
+            ..
+        } => handle_hello(protocol_version, &client_name, &client_version, app_state),
+        RequestPayload::CustomCommand {
+            command_name,
+            params,
+        } => match app_state.commands().execute(&command_name, params, app_state, token) {
+            Ok(message) => ResponsePayload::ok(message),
+            Err(e) => command_error_response(e, app_state),
+        },
+        RequestPayload::ListCommands => {
+            let commands: Vec<CommandInfo> = app_state
+                .commands()
+                .list()
+                .into_iter()
+                .map(|command| CommandInfo {
+                    name: command.name,
+                    description: command.description,
+                    params_schema: command.params_schema,
+                })
+                .collect();
+            ResponsePayload::ok(format!("共 {} 个命令", commands.len()))
+                .with_data(ResponseData::Commands { commands })
+        }
+        RequestPayload::GetUiTree => match current_tree(app_state) {
+            Some(root) => ResponsePayload::ok("界面元素树").with_data(ResponseData::UiTree { root }),
+            None => ResponsePayload::error(ErrorCode::NotRendered, "界面尚未渲染"),
+        },
+        RequestPayload::Click { target } => {
+            perform_element_action(&target, ElementAction::Click, app_state, token)
+        }
+        RequestPayload::Focus { target } => {
+            perform_element_action(&target, ElementAction::Focus, app_state, token)
+        }
+        RequestPayload::SetValue { target, value } => {
+            perform_element_action(&target, ElementAction::SetValue(value), app_state, token)
+        }
+        RequestPayload::Toggle { target } => {
+            perform_element_action(&target, ElementAction::Toggle, app_state, token)
+        }
+        RequestPayload::Scroll { target, dx, dy } => {
+            perform_element_action(&target, ElementAction::Scroll { dx, dy }, app_state, token)
+        }
+        RequestPayload::GetAccessibilityTree => match current_tree(app_state) {
+            Some(root) => {
+                let focused = app_state.elements().focused();
+                ResponsePayload::ok("无障碍树").with_data(ResponseData::AccessibilityTree {
+                    update: accessibility::tree_update(&root, focused.as_deref()),
+                })
+            }
+            None => ResponsePayload::error(ErrorCode::NotRendered, "界面尚未渲染"),
+        },
+        RequestPayload::AccessibilityAction { request } => {
+            app_state.elements().refresh(app_state);
+            match accessibility::perform_action_request(&request, app_state, token) {
+                Ok(message) => ResponsePayload::ok(message),
+                Err(e) => element_error_response(e, app_state),
+            }
+        }
+        RequestPayload::Subscribe { .. } | RequestPayload::Unsubscribe { .. } => {
+            ResponsePayload::error(ErrorCode::InvalidRequest, "订阅只能在 ACP 连接上进行")
+        }
+        RequestPayload::Cancel { .. } => {
+            ResponsePayload::error(ErrorCode::InvalidRequest, "取消只能在 ACP 连接上进行")
+        }
+        RequestPayload::Ping => ResponsePayload::ok("pong"),
+    }
+}
+
+/// 按当前应用状态重建元素树并返回快照。
+/// 窗口只在渲染时同步元素树，之前的 ACP 请求修改的状态可能尚未渲染
+fn current_tree(app_state: &AppState) -> Option<UiElement> {
+    app_state.elements().refresh(app_state);
+    app_state.elements().snapshot()
+}
+
+/// 在元素注册表中查找元素并执行操作
+fn perform_element_action(
+    target: &ElementTarget,
+    action: ElementAction,
+    app_state: &AppState,
+    token: &CancellationToken,
+) -> ResponsePayload {
+    app_state.elements().refresh(app_state);
+    let result = app_state
+        .elements()
+        .resolve(target)
+        .and_then(|element_id| app_state.elements().perform(&element_id, action, app_state, token));
+    match result {
+        Ok(message) => ResponsePayload::ok(message),
+        Err(e) => element_error_response(e, app_state),
+    }
+}
+
+/// 将命令错误转换为带错误码的响应
+fn command_error_response(error: CommandError, app_state: &AppState) -> ResponsePayload {
+    let message = error.to_string();
+    let error = match error {
+        CommandError::UnknownCommand(command_name) => AcpError::new(ErrorCode::UnknownCommand, message)
+            .with_details(json!({
+                "command_name": command_name,
+                "available_commands": app_state.commands().names(),
+            })),
+        CommandError::InvalidParams(e) => AcpError::new(ErrorCode::InvalidParams, message)
+            .with_details(json!({ "path": e.path_under("payload.params") })),
+        CommandError::Failed(_) => AcpError::new(ErrorCode::HandlerFailed, message),
+    };
+    ResponsePayload::failure(error)
+}
+
+/// 将元素操作错误转换为带错误码的响应
+fn element_error_response(error: ElementError, app_state: &AppState) -> ResponsePayload {
+    let message = error.to_string();
+    let error = match error {
+        ElementError::UnknownElement(element_id) => AcpError::new(ErrorCode::UnknownElement, message)
+            .with_details(json!({ "element_id": element_id })),
+        ElementError::UnsupportedAction { element_id, action } => {
+            let supported_actions = app_state
+                .elements()
+                .snapshot()
+                .and_then(|root| root.find(&element_id).map(|element| element.actions.clone()))
+                .unwrap_or_default();
+            AcpError::new(ErrorCode::UnsupportedAction, message).with_details(json!({
+                "element_id": element_id,
+                "action": action,
+                "supported_actions": supported_actions,
+            }))
+        }
+        ElementError::Disabled(element_id) => AcpError::new(ErrorCode::ElementDisabled, message)
+            .with_details(json!({ "element_id": element_id })),
+        ElementError::NotRendered => AcpError::new(ErrorCode::NotRendered, message),
+        ElementError::Query(QueryError::Parse(_)) => AcpError::new(ErrorCode::InvalidParams, message)
+            .with_details(json!({ "path": "payload.target_query" })),
+        ElementError::Query(QueryError::NotFound(query)) => AcpError::new(ErrorCode::UnknownElement, message)
+            .with_details(json!({ "target_query": query })),
+        ElementError::Query(QueryError::Ambiguous { query, candidates }) => {
+            AcpError::new(ErrorCode::AmbiguousTarget, message).with_details(json!({
+                "target_query": query,
+                "candidates": candidates,
+            }))
+        }
+        ElementError::Failed(_) => AcpError::new(ErrorCode::HandlerFailed, message),
+    };
+    ResponsePayload::failure(error)
+}
+
+/// 处理握手请求，协商协议版本并返回应用能力
+fn handle_hello(
+    protocol_version: u32,
+    client_name: &str,
+    client_version: &str,
+    app_state: &AppState,
+) -> ResponsePayload {
+    println!("ACP 客户端握手: {} {} (协议版本 {})", client_name, client_version, protocol_version);
+
+    let welcome = Welcome {
+        protocol_version: protocol_version.min(PROTOCOL_VERSION),
+        app_name: env!("CARGO_PKG_NAME").to_string(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        actions: SUPPORTED_ACTIONS.iter().map(|a| a.to_string()).collect(),
+        commands: app_state.commands().names(),
+        events: SUPPORTED_EVENTS.iter().map(|e| e.to_string()).collect(),
+    };
+
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        return ResponsePayload::failure(
+            AcpError::new(
+                ErrorCode::IncompatibleVersion,
+                format!(
+                    "协议版本不兼容，客户端为 {}，服务端支持 {}-{}",
+                    protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            )
+            .with_details(json!({
+                "min_protocol_version": MIN_PROTOCOL_VERSION,
+                "protocol_version": PROTOCOL_VERSION,
+            })),
+        )
+        .with_data(ResponseData::Welcome(welcome));
+    }
+
+    ResponsePayload::ok("握手成功").with_data(ResponseData::Welcome(welcome))
+}
+
+/// 发送响应
+pub fn send_response(writer: &mut impl Write, seq_id: u64, payload: ResponsePayload) {
+    let response = AcpMessage::Response { seq_id, payload };
+
+    if let Err(e) = writer.write_all(response.to_line().unwrap().as_bytes()) {
+        eprintln!("发送 ACP 响应时出错: {}", e);
+    }
+}
+
+/// 发送错误响应
+pub fn send_error_response(writer: &mut impl Write, seq_id: u64, code: ErrorCode, error_message: &str) {
+    send_response(writer, seq_id, ResponsePayload::error(code, error_message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BackgroundColor;
+
+    fn app_state() -> AppState {
+        let app_state = AppState::new();
+        app_state.commands().register(
+            "CYCLE_COLOR",
+            "循环切换背景颜色",
+            json!({ "type": "object", "properties": {} }),
+            |state, _params, _token| {
+                let color = state.cycle_bg_color();
+                Ok(format!("当前背景: {}", color.name()))
+            },
+        );
+        app_state.elements().register_handler("button", "focus", |_state, _action, _token| {
+            Ok("按钮已获得焦点".to_string())
+        });
+        app_state.elements().update_tree(
+            UiElement::new("root", "window").with_children(vec![UiElement::new("button", "button")]),
+            &app_state,
+        );
+        app_state
+    }
+
+    fn command(command_name: &str) -> RequestPayload {
+        RequestPayload::CustomCommand {
+            command_name: command_name.to_string(),
+            params: None,
+        }
+    }
+
+    fn batch(requests: Vec<RequestPayload>, atomic: bool, app_state: &AppState) -> ResponsePayload {
+        handle_acp_request(
+            RequestPayload::Batch { requests, atomic },
+            app_state,
+            &CancellationToken::new(),
+        )
+    }
+
+    fn result_count(response: &ResponsePayload) -> usize {
+        match &response.data {
+            Some(ResponseData::Batch { results }) => results.len(),
+            other => panic!("预期批处理结果，实际为 {:?}", other),
+        }
+    }
+
+    #[test]
+    fn atomic_batch_rolls_back_after_failure() {
+        let app_state = app_state();
+        let requests = vec![
+            command("CYCLE_COLOR"),
+            RequestPayload::Focus {
+                target: ElementTarget::id("button"),
+            },
+            command("UNKNOWN"),
+            command("CYCLE_COLOR"),
+        ];
+        let response = batch(requests, true, &app_state);
+
+        assert!(!response.success);
+        let error = response.error.as_ref().unwrap();
+        assert_eq!(error.code, ErrorCode::UnknownCommand);
+        let details = error.details.as_ref().unwrap();
+        assert_eq!(details["index"], 2);
+        assert_eq!(details["rolled_back"], true);
+        // 失败之后的请求不再执行
+        assert_eq!(result_count(&response), 3);
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::White);
+        assert_eq!(app_state.elements().focused(), None);
+    }
+
+    #[test]
+    fn non_atomic_batch_keeps_other_results() {
+        let app_state = app_state();
+        let requests = vec![command("CYCLE_COLOR"), command("UNKNOWN"), command("CYCLE_COLOR")];
+        let response = batch(requests, false, &app_state);
+
+        assert!(!response.success);
+        assert_eq!(response.error.as_ref().unwrap().details.as_ref().unwrap()["rolled_back"], false);
+        assert_eq!(result_count(&response), 3);
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::LightGreen);
+    }
+
+    #[test]
+    fn cancelled_atomic_batch_rolls_back() {
+        let app_state = app_state();
+        app_state.commands().register(
+            "CYCLE_AND_CANCEL",
+            "循环背景颜色后取消所在的请求",
+            json!({ "type": "object", "properties": {} }),
+            |state, _params, token| {
+                state.cycle_bg_color();
+                token.cancel();
+                Ok("已取消".to_string())
+            },
+        );
+        let requests = vec![command("CYCLE_AND_CANCEL"), command("CYCLE_COLOR")];
+        let response = batch(requests, true, &app_state);
+
+        assert_eq!(response.error.as_ref().unwrap().code, ErrorCode::Cancelled);
+        assert_eq!(result_count(&response), 1);
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::White);
+
+        // 最后一项执行期间被取消时同样回滚
+        let response = batch(vec![command("CYCLE_AND_CANCEL")], true, &app_state);
+        assert_eq!(response.error.as_ref().unwrap().code, ErrorCode::Cancelled);
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::White);
+    }
+
+    /// 注册 `SLOW` 命令：开始执行后置位 `started`，等待至多 5 秒，未被取消时循环背景颜色
+    fn register_slow_command(app_state: &AppState, started: Arc<AtomicBool>) {
+        app_state.commands().register(
+            "SLOW",
+            "耗时的命令",
+            json!({ "type": "object", "properties": {} }),
+            move |state, _params, token| {
+                started.store(true, Ordering::SeqCst);
+                if token.wait(Duration::from_secs(5)) {
+                    return Ok("已停止".to_string());
+                }
+                state.cycle_bg_color();
+                Ok("已完成".to_string())
+            },
+        );
+    }
+
+    fn request_line(seq_id: u64, payload: RequestPayload) -> String {
+        AcpMessage::Request {
+            seq_id,
+            timeout_ms: None,
+            payload,
+        }
+        .to_line()
+        .unwrap()
+    }
+
+    /// 关闭分发器并取出全部响应
+    fn close_and_collect(dispatcher: Dispatcher, messages: &Receiver<AcpMessage>) -> Vec<(u64, ResponsePayload)> {
+        dispatcher.close();
+        messages
+            .try_iter()
+            .filter_map(|message| match message {
+                AcpMessage::Response { seq_id, payload } => Some((seq_id, payload)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn wait_until(flag: &AtomicBool) {
+        let started = Instant::now();
+        while !flag.load(Ordering::SeqCst) {
+            assert!(started.elapsed() < Duration::from_secs(5), "请求没有开始执行");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn cancelled_requests_get_one_response() {
+        let app_state = Arc::new(app_state());
+        let started = Arc::new(AtomicBool::new(false));
+        register_slow_command(&app_state, started.clone());
+        let (sender, messages) = mpsc::channel();
+        let mut dispatcher = Dispatcher::new(Session::new(1, None), sender, app_state.clone());
+
+        // 1 正在执行，2 在它后面排队
+        dispatcher.handle_message(&request_line(1, command("SLOW")));
+        dispatcher.handle_message(&request_line(2, command("CYCLE_COLOR")));
+        wait_until(&started);
+
+        dispatcher.handle_message(&request_line(10, RequestPayload::Cancel { target_seq_id: 2 }));
+        dispatcher.handle_message(&request_line(11, RequestPayload::Cancel { target_seq_id: 1 }));
+        // 已经取消的请求不能再次取消
+        dispatcher.handle_message(&request_line(12, RequestPayload::Cancel { target_seq_id: 1 }));
+        thread::sleep(Duration::from_millis(50));
+        let responses = close_and_collect(dispatcher, &messages);
+
+        let response_to = |seq_id: u64| {
+            let matching: Vec<_> = responses.iter().filter(|(id, _)| *id == seq_id).collect();
+            assert_eq!(matching.len(), 1, "请求 {} 的响应: {:?}", seq_id, matching);
+            matching[0].1.clone()
+        };
+        for seq_id in [1, 2] {
+            assert_eq!(response_to(seq_id).error.unwrap().code, ErrorCode::Cancelled);
+        }
+        assert!(response_to(10).success);
+        assert!(response_to(11).success);
+        assert_eq!(response_to(12).error.unwrap().code, ErrorCode::InvalidParams);
+        // 排队的请求没有执行，正在执行的请求发现取消后停止
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::White);
+    }
+
+    #[test]
+    fn finished_requests_cannot_be_cancelled() {
+        let app_state = Arc::new(app_state());
+        let (sender, messages) = mpsc::channel();
+        let mut dispatcher = Dispatcher::new(Session::new(1, None), sender, app_state.clone());
+
+        dispatcher.handle_message(&request_line(1, command("CYCLE_COLOR")));
+        thread::sleep(Duration::from_millis(50));
+        dispatcher.handle_message(&request_line(2, RequestPayload::Cancel { target_seq_id: 1 }));
+        let responses = close_and_collect(dispatcher, &messages);
+
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().any(|(seq_id, response)| *seq_id == 1 && response.success));
+        assert!(responses.iter().any(|(seq_id, response)| *seq_id == 2 && !response.success));
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::LightBlue);
+    }
+
+    #[test]
+    fn timed_out_request_is_answered_at_the_deadline() {
+        let app_state = Arc::new(app_state());
+        let started = Arc::new(AtomicBool::new(false));
+        register_slow_command(&app_state, started.clone());
+        let (sender, messages) = mpsc::channel();
+        let mut dispatcher = Dispatcher::new(Session::new(1, None), sender, app_state.clone());
+
+        let line = AcpMessage::Request {
+            seq_id: 1,
+            timeout_ms: Some(20),
+            payload: command("SLOW"),
+        }
+        .to_line()
+        .unwrap();
+        dispatcher.handle_message(&line);
+        wait_until(&started);
+        thread::sleep(Duration::from_millis(100));
+        let responses = close_and_collect(dispatcher, &messages);
+
+        assert_eq!(responses.len(), 1);
+        let error = responses[0].1.error.as_ref().unwrap();
+        assert_eq!(error.code, ErrorCode::TimedOut);
+        assert_eq!(error.details.as_ref().unwrap()["timeout_ms"], 20);
+    }
+
+    #[test]
+    fn start_fails_when_the_token_cannot_be_written() {
+        // 发现目录位于普通文件之下，无法创建
+        let blocker = std::env::temp_dir().join(format!("acp-token-blocker-{}", std::process::id()));
+        std::fs::write(&blocker, b"").unwrap();
+        let config = AcpServerConfig {
+            addr: Some("127.0.0.1:0".to_string()),
+            discovery_dir: Some(blocker.join("agentkit")),
+            auth_token: Some("token".to_string()),
+            ..AcpServerConfig::default()
+        };
+
+        let result = AcpServer::start(config, Arc::new(AppState::new()));
+        std::fs::remove_file(&blocker).unwrap();
+        let error = result.err().expect("令牌无法写入时服务器不应启动");
+        assert!(error.to_string().contains("认证令牌"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// 请求的执行状态，只能从 `Running` 转换一次
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Running,
+    Finished,
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Debug)]
+struct Inner {
+    status: Mutex<Status>,
+    changed: Condvar,
+    deadline: Option<Instant>,
+}
+
+/// 请求的取消令牌，命令和元素的处理函数通过它得知请求已被取消或超时。
+/// 耗时的处理函数应定期检查 `is_cancelled`，取消后的执行结果会被丢弃
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    /// 不会超时的令牌，界面事件触发的操作使用它
+    pub fn new() -> Self {
+        Self::with_deadline(None)
+    }
+
+    /// 在 `deadline` 之后由服务端标记为超时的令牌
+    pub(crate) fn with_deadline(deadline: Option<Instant>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                status: Mutex::new(Status::Running),
+                changed: Condvar::new(),
+                deadline,
+            }),
+        }
+    }
+
+    /// 请求是否已被取消或已超时
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.status(), Status::Cancelled | Status::TimedOut)
+    }
+
+    /// 请求的截止时间，没有设置超时时为 `None`
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline
+    }
+
+    /// 等待至多 `timeout`，请求被取消、超时或完成时提前返回。返回请求是否已被取消或已超时
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let status = self.inner.status.lock().unwrap();
+        let (status, _) = self
+            .inner
+            .changed
+            .wait_timeout_while(status, timeout, |status| *status == Status::Running)
+            .unwrap();
+        matches!(*status, Status::Cancelled | Status::TimedOut)
+    }
+
+    /// 标记为已取消，请求已结束时返回 `false`
+    pub(crate) fn cancel(&self) -> bool {
+        self.transition(Status::Cancelled)
+    }
+
+    /// 标记为已超时，请求已结束时返回 `false`
+    pub(crate) fn time_out(&self) -> bool {
+        self.transition(Status::TimedOut)
+    }
+
+    /// 标记为正常完成，请求已被取消或超时时返回 `false`，此时不应再发送响应
+    pub(crate) fn finish(&self) -> bool {
+        self.transition(Status::Finished)
+    }
+
+    /// 等待到截止时间，请求在此之前结束时提前返回。没有截止时间时立即返回
+    pub(crate) fn wait_for_deadline(&self) {
+        let Some(deadline) = self.inner.deadline else {
+            return;
+        };
+        let mut status = self.inner.status.lock().unwrap();
+        while *status == Status::Running {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            status = self.inner.changed.wait_timeout(status, remaining).unwrap().0;
+        }
+    }
+
+    fn status(&self) -> Status {
+        *self.inner.status.lock().unwrap()
+    }
+
+    /// 从 `Running` 转换到 `status`，同一请求只有一次转换会成功，
+    /// 成功的一方负责发送响应
+    fn transition(&self, status: Status) -> bool {
+        let mut current = self.inner.status.lock().unwrap();
+        if *current != Status::Running {
+            return false;
+        }
+        *current = status;
+        self.inner.changed.notify_all();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn only_the_first_transition_succeeds() {
+        let token = CancellationToken::new();
+        assert!(!token.is_cancelled());
+        assert!(token.cancel());
+        assert!(token.is_cancelled());
+        assert!(!token.finish());
+        assert!(!token.time_out());
+
+        let token = CancellationToken::new();
+        assert!(token.finish());
+        assert!(!token.cancel());
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn clones_share_the_status() {
+        let token = CancellationToken::new();
+        let handler_token = token.clone();
+        assert!(token.time_out());
+        assert!(handler_token.is_cancelled());
+    }
+
+    #[test]
+    fn wait_returns_when_cancelled() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let started = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        assert!(token.wait(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wait_reports_finished_requests_as_not_cancelled() {
+        let token = CancellationToken::new();
+        assert!(!token.wait(Duration::from_millis(10)));
+        assert!(token.finish());
+        assert!(!token.wait(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn wait_for_deadline_stops_at_the_deadline() {
+        let token = CancellationToken::with_deadline(Some(Instant::now() + Duration::from_millis(20)));
+        token.wait_for_deadline();
+        assert!(token.deadline().unwrap() <= Instant::now());
+        assert!(token.time_out());
+
+        // 没有截止时间时立即返回
+        let token = CancellationToken::new();
+        token.wait_for_deadline();
+        assert!(!token.is_cancelled());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{AppState, CancellationToken};
+use acp::schema::{self, SchemaError};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, RwLock},
+};
+
+/// 命令处理函数，返回执行结果的说明。参数已经通过了 `params_schema` 的校验。
+/// 耗时的命令应通过取消令牌检查请求是否已被取消
+pub type CommandHandler = Arc<
+    dyn Fn(&AppState, Option<Value>, &CancellationToken) -> anyhow::Result<String> + Send + Sync,
+>;
+
+/// 已注册的命令
+#[derive(Clone)]
+pub struct Command {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema，执行前按它校验参数，支持的关键字见 `acp::schema`
+    pub params_schema: Value,
+    handler: CommandHandler,
+}
+
+/// 执行命令时的错误
+#[derive(Debug)]
+pub enum CommandError {
+    /// 命令未注册
+    UnknownCommand(String),
+    /// 参数不满足命令的 `params_schema`
+    InvalidParams(SchemaError),
+    /// 命令处理函数返回了错误
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand(name) => write!(f, "未知命令: {}", name),
+            CommandError::InvalidParams(e) => write!(f, "{}", e),
+            CommandError::Failed(e) => write!(f, "命令执行失败: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// 命令注册表，按名称查找并分发命令
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: RwLock<BTreeMap<String, Command>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 注册命令，同名命令会被替换
+    pub fn register(
+        &self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        params_schema: Value,
+        handler: impl Fn(&AppState, Option<Value>, &CancellationToken) -> anyhow::Result<String>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        let name = name.into();
+        let command = Command {
+            name: name.clone(),
+            description: description.into(),
+            params_schema,
+            handler: Arc::new(handler),
+        };
+        self.commands.write().unwrap().insert(name, command);
+    }
+
+    /// 查找命令
+    pub fn get(&self, name: &str) -> Option<Command> {
+        self.commands.read().unwrap().get(name).cloned()
+    }
+
+    /// 所有已注册的命令，按名称排序
+    pub fn list(&self) -> Vec<Command> {
+        self.commands.read().unwrap().values().cloned().collect()
+    }
+
+    /// 所有已注册的命令名称，按名称排序
+    pub fn names(&self) -> Vec<String> {
+        self.commands.read().unwrap().keys().cloned().collect()
+    }
+
+    /// 校验参数后执行命令。没有参数时按空对象校验
+    pub fn execute(
+        &self,
+        name: &str,
+        params: Option<Value>,
+        app_state: &AppState,
+        token: &CancellationToken,
+    ) -> Result<String, CommandError> {
+        // 先取出处理函数再执行，避免处理函数中访问注册表时死锁
+        let command = self
+            .get(name)
+            .ok_or_else(|| CommandError::UnknownCommand(name.to_string()))?;
+        schema::validate(&command.params_schema, params.as_ref().unwrap_or(&json!({})))
+            .map_err(CommandError::InvalidParams)?;
+        (command.handler)(app_state, params, token).map_err(CommandError::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 注册一个带参数 Schema 的命令，返回处理函数被调用的次数
+    fn register_set_color(app_state: &AppState) -> Arc<AtomicUsize> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        app_state.commands().register(
+            "SET_COLOR",
+            "设置颜色",
+            json!({
+                "type": "object",
+                "properties": {
+                    "color": { "type": "string", "enum": ["white", "light_blue"] },
+                    "h": { "type": "number", "minimum": 0, "maximum": 360 }
+                },
+                "additionalProperties": false,
+                "minProperties": 1
+            }),
+            move |_state, params, _token| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(format!("参数: {}", params.unwrap_or_default()))
+            },
+        );
+        calls
+    }
+
+    fn execute(app_state: &AppState, params: Option<Value>) -> Result<String, CommandError> {
+        app_state
+            .commands()
+            .execute("SET_COLOR", params, app_state, &CancellationToken::new())
+    }
+
+    #[test]
+    fn invalid_params_are_rejected_before_the_handler_runs() {
+        let app_state = AppState::new();
+        let calls = register_set_color(&app_state);
+
+        let cases = [
+            (Some(json!({ "color": "red" })), "color"),
+            (Some(json!({ "h": 400 })), "h"),
+            (Some(json!({ "h": "120" })), "h"),
+            (Some(json!({ "colour": "white" })), "colour"),
+            (Some(json!([1, 2])), ""),
+            // 没有参数时按空对象校验
+            (None, ""),
+        ];
+        for (params, path) in cases {
+            match execute(&app_state, params.clone()) {
+                Err(CommandError::InvalidParams(e)) => assert_eq!(e.path, path, "参数 {:?}", params),
+                other => panic!("参数 {:?} 应被拒绝，实际为 {:?}", params, other),
+            }
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn valid_params_reach_the_handler() {
+        let app_state = AppState::new();
+        let calls = register_set_color(&app_state);
+
+        assert!(execute(&app_state, Some(json!({ "color": "light_blue" }))).is_ok());
+        assert!(execute(&app_state, Some(json!({ "h": 120.5 }))).is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unknown_commands_are_reported() {
+        let app_state = AppState::new();
+        let result = app_state
+            .commands()
+            .execute("MISSING", None, &app_state, &CancellationToken::new());
+        assert!(matches!(result, Err(CommandError::UnknownCommand(name)) if name == "MISSING"));
+    }
+}
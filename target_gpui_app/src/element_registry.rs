@@ -0,0 +1,277 @@
+use crate::{AppState, CancellationToken};
+use acp::{query::QueryError, ElementBounds, ElementTarget, EventPayload, UiElement};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+};
+
+/// 对元素执行的操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElementAction {
+    Click,
+    Focus,
+    SetValue(Value),
+    Toggle,
+    Scroll { dx: f32, dy: f32 },
+}
+
+impl ElementAction {
+    /// 操作名称，与 ACP 的 `action` 一致
+    pub fn name(&self) -> &'static str {
+        match self {
+            ElementAction::Click => "click",
+            ElementAction::Focus => "focus",
+            ElementAction::SetValue(_) => "set_value",
+            ElementAction::Toggle => "toggle",
+            ElementAction::Scroll { .. } => "scroll",
+        }
+    }
+}
+
+/// 元素操作处理函数，返回执行结果的说明
+pub type ElementHandler = Arc<
+    dyn Fn(&AppState, &ElementAction, &CancellationToken) -> anyhow::Result<String> + Send + Sync,
+>;
+
+/// 根据应用状态生成元素树的函数，结构与视图的 `render` 保持一致
+pub type Describer = Arc<dyn Fn(&AppState) -> UiElement + Send + Sync>;
+
+/// 执行元素操作时的错误
+#[derive(Debug)]
+pub enum ElementError {
+    /// 元素不存在
+    UnknownElement(String),
+    /// 元素不支持该操作
+    UnsupportedAction { element_id: String, action: String },
+    /// 元素当前不可用
+    Disabled(String),
+    /// 界面尚未渲染，无法查找元素
+    NotRendered,
+    /// `target_query` 无法解析为唯一的元素
+    Query(QueryError),
+    /// 处理函数返回了错误
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for ElementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElementError::UnknownElement(id) => write!(f, "未知元素: {}", id),
+            ElementError::UnsupportedAction { element_id, action } => {
+                write!(f, "元素 {} 不支持操作 {}", element_id, action)
+            }
+            ElementError::Disabled(id) => write!(f, "元素 {} 当前不可用", id),
+            ElementError::NotRendered => write!(f, "界面尚未渲染"),
+            ElementError::Query(e) => write!(f, "{}", e),
+            ElementError::Failed(e) => write!(f, "元素操作失败: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ElementError {}
+
+/// 界面元素注册表，保存最近一次渲染的元素树和各元素的操作处理函数
+#[derive(Default)]
+pub struct ElementRegistry {
+    tree: Mutex<Option<UiElement>>,
+    /// 布局后得到的元素位置，按元素 ID 保存
+    bounds: Mutex<HashMap<String, ElementBounds>>,
+    /// 元素 ID -> 操作名称 -> 处理函数
+    handlers: RwLock<HashMap<String, BTreeMap<&'static str, ElementHandler>>>,
+    /// 通过 `focus` 操作获得焦点的元素
+    focused: Mutex<Option<String>>,
+    /// 按当前应用状态重建元素树，ACP 修改的状态不必等到视图重新渲染
+    describer: RwLock<Option<Describer>>,
+}
+
+impl ElementRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 更新元素树，在视图渲染时调用。
+    /// 与上一次渲染相比新增的元素会推送 `element_added` 事件，首次渲染不推送
+    pub fn update_tree(&self, root: UiElement, app_state: &AppState) {
+        let mut events = Vec::new();
+        {
+            let mut tree = self.tree.lock().unwrap();
+            if let Some(old_root) = tree.as_ref() {
+                let mut old_ids = HashSet::new();
+                collect_ids(old_root, &mut old_ids);
+                collect_added(&root, None, &old_ids, &mut events);
+            }
+
+            // 获得焦点的元素已不在界面中时，焦点随之消失
+            let mut focused = self.focused.lock().unwrap();
+            if focused.as_deref().is_some_and(|id| root.find(id).is_none()) {
+                *focused = None;
+                events.push(EventPayload::FocusChanged { element_id: None });
+            }
+
+            *tree = Some(root);
+        }
+
+        for event in events {
+            app_state.events().emit(event);
+        }
+    }
+
+    /// 设置生成元素树的函数，在视图创建时调用
+    pub fn set_describer(
+        &self,
+        describer: impl Fn(&AppState) -> UiElement + Send + Sync + 'static,
+    ) {
+        *self.describer.write().unwrap() = Some(Arc::new(describer));
+    }
+
+    /// 按当前应用状态重建元素树。ACP 请求修改状态后窗口不一定重新渲染，
+    /// 读取或操作元素前调用。尚未渲染或没有设置生成函数时不做任何事
+    pub fn refresh(&self, app_state: &AppState) {
+        let Some(describer) = self.describer.read().unwrap().clone() else {
+            return;
+        };
+        if self.tree.lock().unwrap().is_none() {
+            return;
+        }
+        self.update_tree(describer(app_state), app_state);
+    }
+
+    /// 更新元素位置，在布局完成后调用
+    pub fn update_bounds(&self, id: &str, bounds: ElementBounds) {
+        self.bounds.lock().unwrap().insert(id.to_string(), bounds);
+    }
+
+    /// 为元素注册操作处理函数，界面事件和 ACP 请求都通过它执行
+    pub fn register_handler(
+        &self,
+        id: impl Into<String>,
+        action: &'static str,
+        handler: impl Fn(&AppState, &ElementAction, &CancellationToken) -> anyhow::Result<String>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.handlers
+            .write()
+            .unwrap()
+            .entry(id.into())
+            .or_default()
+            .insert(action, Arc::new(handler));
+    }
+
+    /// 获取带位置信息和可用操作的元素树快照，尚未渲染时为 `None`
+    pub fn snapshot(&self) -> Option<UiElement> {
+        let mut root = self.tree.lock().unwrap().clone()?;
+        for (id, bounds) in self.bounds.lock().unwrap().iter() {
+            if let Some(element) = root.find_mut(id) {
+                element.bounds = Some(*bounds);
+            }
+        }
+        for (id, actions) in self.handlers.read().unwrap().iter() {
+            if let Some(element) = root.find_mut(id) {
+                element.actions = actions.keys().map(|a| a.to_string()).collect();
+            }
+        }
+        Some(root)
+    }
+
+    /// 将操作目标解析为元素 ID
+    pub fn resolve(&self, target: &ElementTarget) -> Result<String, ElementError> {
+        if let (Some(id), None) = (&target.element_id, &target.target_query) {
+            return Ok(id.clone());
+        }
+
+        let tree = self.tree.lock().unwrap();
+        let root = tree.as_ref().ok_or(ElementError::NotRendered)?;
+        target
+            .resolve(root)
+            .map(|element| element.id.clone())
+            .map_err(ElementError::Query)
+    }
+
+    /// 对元素执行操作
+    pub fn perform(
+        &self,
+        id: &str,
+        action: ElementAction,
+        app_state: &AppState,
+        token: &CancellationToken,
+    ) -> Result<String, ElementError> {
+        let enabled = match self.tree.lock().unwrap().as_ref().and_then(|root| root.find(id)) {
+            Some(element) => element.enabled,
+            None => return Err(ElementError::UnknownElement(id.to_string())),
+        };
+        if !enabled {
+            return Err(ElementError::Disabled(id.to_string()));
+        }
+
+        // 先取出处理函数再执行，避免处理函数中访问注册表时死锁
+        let handler = self
+            .handlers
+            .read()
+            .unwrap()
+            .get(id)
+            .and_then(|actions| actions.get(action.name()))
+            .cloned()
+            .ok_or_else(|| ElementError::UnsupportedAction {
+                element_id: id.to_string(),
+                action: action.name().to_string(),
+            })?;
+
+        let message = handler(app_state, &action, token).map_err(ElementError::Failed)?;
+
+        if action == ElementAction::Focus {
+            self.set_focused(Some(id.to_string()), app_state);
+        }
+
+        Ok(message)
+    }
+
+    /// 当前获得焦点的元素
+    pub fn focused(&self) -> Option<String> {
+        self.focused.lock().unwrap().clone()
+    }
+
+    /// 设置获得焦点的元素，焦点有变化时推送 `focus_changed`
+    pub fn set_focused(&self, element_id: Option<String>, app_state: &AppState) {
+        let changed = {
+            let mut focused = self.focused.lock().unwrap();
+            let changed = *focused != element_id;
+            focused.clone_from(&element_id);
+            changed
+        };
+        if changed {
+            app_state
+                .events()
+                .emit(EventPayload::FocusChanged { element_id });
+        }
+    }
+}
+
+fn collect_ids(element: &UiElement, ids: &mut HashSet<String>) {
+    ids.insert(element.id.clone());
+    for child in &element.children {
+        collect_ids(child, ids);
+    }
+}
+
+/// 查找新增的元素，新增的子树只生成一个事件
+fn collect_added(
+    element: &UiElement,
+    parent_id: Option<&str>,
+    old_ids: &HashSet<String>,
+    events: &mut Vec<EventPayload>,
+) {
+    if !old_ids.contains(&element.id) {
+        events.push(EventPayload::ElementAdded {
+            parent_id: parent_id.map(str::to_string),
+            element: element.clone(),
+        });
+        return;
+    }
+    for child in &element.children {
+        collect_added(child, Some(&element.id), old_ids, events);
+    }
+}
@@ -0,0 +1,105 @@
+use acp::{AcpError, AcpMessage, ErrorCode, EventPayload};
+use serde_json::json;
+use std::sync::{mpsc::Sender, Mutex};
+
+/// 应用会推送的事件，在握手时告知客户端
+pub const SUPPORTED_EVENTS: &[&str] = &[
+    "state_changed",
+    "element_added",
+    "focus_changed",
+    "window_closed",
+];
+
+/// ACP 连接上的一个订阅
+struct Subscription {
+    connection_id: u64,
+    /// 订阅请求的 `seq_id`，推送的事件消息使用同一个 `seq_id`
+    subscription_id: u64,
+    /// 订阅的事件名称，为空表示全部事件
+    events: Vec<String>,
+    sender: Sender<AcpMessage>,
+}
+
+impl Subscription {
+    fn accepts(&self, event: &EventPayload) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event.event_name())
+    }
+}
+
+/// 事件总线，把应用事件推送给订阅了它们的 ACP 连接
+#[derive(Default)]
+pub struct EventBus {
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加订阅，事件通过 `sender` 交给连接的写线程发送
+    pub fn subscribe(
+        &self,
+        connection_id: u64,
+        subscription_id: u64,
+        events: Vec<String>,
+        sender: Sender<AcpMessage>,
+    ) -> Result<(), AcpError> {
+        if let Some((index, unknown)) = events
+            .iter()
+            .enumerate()
+            .find(|(_, e)| !SUPPORTED_EVENTS.contains(&e.as_str()))
+        {
+            return Err(
+                AcpError::new(ErrorCode::InvalidParams, format!("未知事件: {}", unknown)).with_details(
+                    json!({
+                        "path": format!("payload.events[{}]", index),
+                        "supported_events": SUPPORTED_EVENTS,
+                    }),
+                ),
+            );
+        }
+
+        self.subscriptions.lock().unwrap().push(Subscription {
+            connection_id,
+            subscription_id,
+            events,
+            sender,
+        });
+        Ok(())
+    }
+
+    /// 取消连接上的一个订阅，返回订阅是否存在
+    pub fn unsubscribe(&self, connection_id: u64, subscription_id: u64) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|s| {
+            !(s.connection_id == connection_id && s.subscription_id == subscription_id)
+        });
+        subscriptions.len() != before
+    }
+
+    /// 取消连接上的全部订阅，在连接关闭时调用
+    pub fn unsubscribe_connection(&self, connection_id: u64) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|s| s.connection_id != connection_id);
+    }
+
+    /// 推送事件，只发送给订阅了该事件的连接
+    pub fn emit(&self, event: EventPayload) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        // 连接的写线程已退出时顺便清理订阅
+        subscriptions.retain(|subscription| {
+            if !subscription.accepts(&event) {
+                return true;
+            }
+            let message = AcpMessage::Event {
+                seq_id: subscription.subscription_id,
+                payload: event.clone(),
+            };
+            subscription.sender.send(message).is_ok()
+        });
+    }
+}
@@ -1,77 +1,320 @@
+pub mod accessibility;
+mod acp_server;
+mod cancellation;
+mod command_registry;
+mod element_registry;
+mod event_bus;
+mod transport;
+mod websocket;
+
+use acp::{ElementBounds, EventPayload, UiElement};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
 use gpui::{
-    self, div, 
+    self, div, prelude::FluentBuilder,
     DismissEvent, EventEmitter, Render, Styled, IntoElement, Context,
-    ParentElement, Window,
-    Hsla, black, white, AppContext,
+    ParentElement, Window, InteractiveElement, StatefulInteractiveElement,
+    Hsla, black, white, Bounds, Pixels, FocusHandle, ScrollWheelEvent,
 };
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::{
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    sync::{Arc, Mutex, TryLockError},
+};
+
+pub use acp_server::{
+    handle_acp_connection, handle_acp_request, send_error_response, send_response, AcpServer,
+    AcpServerConfig, Session,
+};
+pub use cancellation::CancellationToken;
+pub use command_registry::{Command, CommandError, CommandHandler, CommandRegistry};
+pub use element_registry::{
+    Describer, ElementAction, ElementError, ElementHandler, ElementRegistry,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+pub use event_bus::{EventBus, SUPPORTED_EVENTS};
+pub use transport::{default_unix_socket_path, AcpStream};
+
+/// 界面元素 ID，ACP 通过它们定位元素
+pub mod element_ids {
+    pub const ROOT: &str = "root";
+    pub const COLOR_LABEL: &str = "color_label";
+    pub const CYCLE_COLOR_BUTTON: &str = "cycle_color_button";
+    pub const SHOW_HSL_CHECKBOX: &str = "show_hsl_checkbox";
+    pub const HUE_SLIDER: &str = "hue_slider";
+}
+
+/// 按钮上显示的文本
+const CYCLE_COLOR_BUTTON_TEXT: &str = "点击按钮或通过ACP改变颜色";
+
+/// 滚动色相滑块时每逻辑像素改变的色相，单位为度
+const HUE_PER_PIXEL: f32 = 0.25;
+
+/// HSL 颜色，色相 `h` 的单位为度 (0-360)，饱和度 `s` 和亮度 `l` 的范围为 0-1
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Hsl {
+    pub h: f32,
+    pub s: f32,
+    pub l: f32,
+}
 
 /// 应用程序的背景颜色枚举
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BackgroundColor {
     White,
     LightBlue,
     LightGreen,
+    /// 通过 ACP 指定的任意颜色
+    Custom(Hsl),
 }
 
 impl BackgroundColor {
-    /// 循环到下一个颜色
+    /// 可以按名称指定的预设颜色，按循环顺序排列
+    pub const PRESETS: [BackgroundColor; 3] = [
+        BackgroundColor::White,
+        BackgroundColor::LightBlue,
+        BackgroundColor::LightGreen,
+    ];
+
+    /// 循环到下一个颜色，自定义颜色之后回到白色
     pub fn next(&self) -> Self {
         match self {
             BackgroundColor::White => BackgroundColor::LightBlue,
             BackgroundColor::LightBlue => BackgroundColor::LightGreen,
-            BackgroundColor::LightGreen => BackgroundColor::White,
+            BackgroundColor::LightGreen | BackgroundColor::Custom(_) => BackgroundColor::White,
+        }
+    }
+
+    /// 按 ACP 参数中的名称查找预设颜色，例如 `light_blue`
+    pub fn from_preset_name(name: &str) -> Option<Self> {
+        Self::PRESETS
+            .into_iter()
+            .find(|color| color.preset_name() == Some(name))
+    }
+
+    /// 预设颜色在 ACP 参数中的名称，自定义颜色为 `None`
+    pub fn preset_name(&self) -> Option<&'static str> {
+        match self {
+            BackgroundColor::White => Some("white"),
+            BackgroundColor::LightBlue => Some("light_blue"),
+            BackgroundColor::LightGreen => Some("light_green"),
+            BackgroundColor::Custom(_) => None,
         }
     }
 
     /// 获取颜色名称
-    pub fn name(&self) -> &'static str {
+    pub fn name(&self) -> String {
         match self {
-            BackgroundColor::White => "White",
-            BackgroundColor::LightBlue => "Light Blue",
-            BackgroundColor::LightGreen => "Light Green",
+            BackgroundColor::White => "White".to_string(),
+            BackgroundColor::LightBlue => "Light Blue".to_string(),
+            BackgroundColor::LightGreen => "Light Green".to_string(),
+            BackgroundColor::Custom(hsl) => format!("HSL({}, {}, {})", hsl.h, hsl.s, hsl.l),
         }
     }
-    
-    /// 转换为GPUI Hsla颜色
-    pub fn to_rgb(&self) -> Hsla {
+
+    /// 颜色的 HSL 值
+    pub fn hsl(&self) -> Hsl {
         match self {
-            BackgroundColor::White => Hsla { h: 0.0, s: 0.0, l: 1.0, a: 1.0 },
-            BackgroundColor::LightBlue => Hsla { h: 210.0, s: 0.5, l: 0.8, a: 1.0 },
-            BackgroundColor::LightGreen => Hsla { h: 120.0, s: 0.5, l: 0.8, a: 1.0 },
+            BackgroundColor::White => Hsl { h: 0.0, s: 0.0, l: 1.0 },
+            BackgroundColor::LightBlue => Hsl { h: 210.0, s: 0.5, l: 0.8 },
+            BackgroundColor::LightGreen => Hsl { h: 120.0, s: 0.5, l: 0.8 },
+            BackgroundColor::Custom(hsl) => *hsl,
         }
     }
+    
+    /// 转换为GPUI Hsla颜色，GPUI 的色相范围是 0-1
+    pub fn to_rgb(&self) -> Hsla {
+        let Hsl { h, s, l } = self.hsl();
+        Hsla { h: h / 360.0, s, l, a: 1.0 }
+    }
+}
+
+/// 应用状态的快照，原子批处理失败时用于回滚
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppSnapshot {
+    bg_color: BackgroundColor,
+    show_hsl: bool,
+    /// 获得焦点的元素
+    focused: Option<String>,
+}
+
+/// 需要在界面线程上完成的更新。ACP 请求在工作线程中执行，不能直接访问窗口
+#[derive(Debug, Clone, PartialEq)]
+pub enum UiUpdate {
+    /// 把 GPUI 焦点移到元素上，`None` 表示取消焦点
+    Focus(Option<String>),
+    /// 应用状态已变化，重新绘制窗口
+    Redraw,
 }
 
 /// 应用程序状态
 pub struct AppState {
     current_bg_color: Arc<Mutex<BackgroundColor>>,
+    /// 是否在颜色标签中显示 HSL 数值，由复选框切换
+    show_hsl: Mutex<bool>,
+    commands: CommandRegistry,
+    elements: ElementRegistry,
+    events: EventBus,
+    /// ACP 请求在持有该锁时执行，原子批处理执行期间不会穿插其他连接的请求
+    transaction: Mutex<()>,
+    /// 把界面更新交给视图，视图创建之前为 `None`
+    ui_updates: Mutex<Option<UnboundedSender<UiUpdate>>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         Self {
             current_bg_color: Arc::new(Mutex::new(BackgroundColor::White)),
+            show_hsl: Mutex::new(false),
+            commands: CommandRegistry::new(),
+            elements: ElementRegistry::new(),
+            events: EventBus::new(),
+            transaction: Mutex::new(()),
+            ui_updates: Mutex::new(None),
         }
     }
 
+    /// 事件总线，状态变化通过它推送给订阅的 ACP 客户端
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// 当前应用状态，随 `state_changed` 事件推送
+    pub fn state(&self) -> Value {
+        json!({ "bg_color": self.get_bg_color().name(), "show_hsl": self.show_hsl() })
+    }
+
+    /// 界面元素注册表，保存 `RootView` 最近一次渲染的元素树
+    pub fn elements(&self) -> &ElementRegistry {
+        &self.elements
+    }
+
+    /// 应用的命令注册表，可通过 ACP 调用的命令都注册在这里
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.commands
+    }
+
     /// 循环背景颜色
     pub fn cycle_bg_color(&self) -> BackgroundColor {
-        let mut color = self.current_bg_color.lock().unwrap();
-        *color = color.next();
-        *color
+        let color = {
+            let mut color = self.current_bg_color.lock().unwrap();
+            *color = color.next();
+            *color
+        };
+        self.state_changed();
+        color
+    }
+
+    /// 设置背景颜色，颜色有变化时推送 `state_changed`
+    pub fn set_bg_color(&self, color: BackgroundColor) -> BackgroundColor {
+        let changed = {
+            let mut current = self.current_bg_color.lock().unwrap();
+            let changed = *current != color;
+            *current = color;
+            changed
+        };
+        if changed {
+            self.state_changed();
+        }
+        color
+    }
+
+    /// 状态变化后推送 `state_changed`，并让窗口重新绘制
+    fn state_changed(&self) {
+        self.events.emit(EventPayload::StateChanged { state: self.state() });
+        self.request_ui_update(UiUpdate::Redraw);
     }
 
     /// 获取当前背景颜色
     pub fn get_bg_color(&self) -> BackgroundColor {
         *self.current_bg_color.lock().unwrap()
     }
+
+    /// 是否在颜色标签中显示 HSL 数值
+    pub fn show_hsl(&self) -> bool {
+        *self.show_hsl.lock().unwrap()
+    }
+
+    /// 设置是否显示 HSL 数值，有变化时推送 `state_changed`
+    pub fn set_show_hsl(&self, show: bool) -> bool {
+        let changed = {
+            let mut current = self.show_hsl.lock().unwrap();
+            let changed = *current != show;
+            *current = show;
+            changed
+        };
+        if changed {
+            self.state_changed();
+        }
+        show
+    }
+
+    /// 切换是否显示 HSL 数值，返回切换后的值
+    pub fn toggle_show_hsl(&self) -> bool {
+        let show = {
+            let mut show = self.show_hsl.lock().unwrap();
+            *show = !*show;
+            *show
+        };
+        self.state_changed();
+        show
+    }
+
+    /// 记录当前状态
+    pub fn snapshot(&self) -> AppSnapshot {
+        AppSnapshot {
+            bg_color: self.get_bg_color(),
+            show_hsl: self.show_hsl(),
+            focused: self.elements.focused(),
+        }
+    }
+
+    /// 恢复到快照时的状态，状态有变化时推送 `state_changed` 或 `focus_changed`，
+    /// 焦点有变化时同时移动窗口中的焦点
+    pub fn restore(&self, snapshot: &AppSnapshot) {
+        self.set_bg_color(snapshot.bg_color);
+        self.set_show_hsl(snapshot.show_hsl);
+        if self.elements.focused() != snapshot.focused {
+            self.elements.set_focused(snapshot.focused.clone(), self);
+            self.request_ui_update(UiUpdate::Focus(snapshot.focused.clone()));
+        }
+    }
+
+    /// 接收界面更新，在视图创建时调用。再次调用后，之前的接收方不再收到更新
+    pub fn ui_updates(&self) -> UnboundedReceiver<UiUpdate> {
+        let (sender, receiver) = mpsc::unbounded();
+        *self.ui_updates.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// 请求视图在界面线程完成更新，还没有视图时忽略
+    pub fn request_ui_update(&self, update: UiUpdate) {
+        if let Some(sender) = self.ui_updates.lock().unwrap().as_ref() {
+            let _ = sender.unbounded_send(update);
+        }
+    }
+
+    /// 持有事务锁执行 `f`。处理函数 panic 后锁仍然可用
+    pub fn transaction<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _guard = self
+            .transaction
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f()
+    }
+
+    /// 与 [`AppState::transaction`] 相同，但锁正被持有时不等待，直接返回 `None`。
+    /// 界面线程用它避免在 ACP 请求执行期间卡住
+    pub fn try_transaction<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+        let _guard = match self.transaction.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(f())
+    }
 }
 
 /// 自定义操作类型，用于应用程序内部通信
@@ -83,18 +326,245 @@ pub enum AppAction {
 /// 应用根视图
 pub struct RootView {
     app_state: Arc<AppState>,
+    /// 按钮的焦点，ACP 的 focus 请求通过它移动窗口中的焦点
+    button_focus: FocusHandle,
+    /// 等待执行的界面事件，ACP 请求持有事务锁时按顺序留到之后的帧执行
+    pending_actions: VecDeque<(&'static str, ElementAction)>,
+    /// 是否正在等待事务锁，等待期间已安排在下一帧重试
+    waiting_for_lock: bool,
 }
 
 impl RootView {
-    pub fn new(app_state: Arc<AppState>, _cx: &mut Window) -> Self {
-        Self { app_state }
+    pub fn new(app_state: Arc<AppState>, window: &mut Window, cx: &mut Context<Self>) -> Self {
+        register_element_handlers(&app_state);
+        app_state.elements().set_describer(describe);
+
+        // ACP 请求在工作线程中执行，需要访问窗口的更新交给界面线程完成
+        let mut updates = app_state.ui_updates();
+        cx.spawn_in(window, async move |this, cx| {
+            while let Some(update) = updates.next().await {
+                let applied = this.update_in(cx, |view, window, cx| {
+                    view.apply_ui_update(update, window, cx)
+                });
+                if applied.is_err() {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+        Self {
+            app_state,
+            button_focus: cx.focus_handle(),
+            pending_actions: VecDeque::new(),
+            waiting_for_lock: false,
+        }
+    }
+
+    /// 描述当前界面的元素树，结构与 `render` 保持一致
+    pub fn describe(&self) -> UiElement {
+        describe(&self.app_state)
+    }
+
+    /// 在界面线程完成 ACP 请求带来的更新，之后重新绘制窗口
+    fn apply_ui_update(&mut self, update: UiUpdate, window: &mut Window, cx: &mut Context<Self>) {
+        match update {
+            UiUpdate::Focus(Some(id)) if id == element_ids::CYCLE_COLOR_BUTTON => {
+                window.focus(&self.button_focus);
+            }
+            UiUpdate::Focus(Some(id)) => eprintln!("元素 {} 没有可以获得的焦点", id),
+            UiUpdate::Focus(None) => window.blur(),
+            // 下面的 notify 即可触发重绘
+            UiUpdate::Redraw => {}
+        }
+        cx.notify();
+    }
+
+    /// 处理界面事件，与 ACP 请求一样持有事务锁，原子批处理执行期间不会穿插界面操作
+    fn perform_ui_action(
+        &mut self,
+        id: &'static str,
+        action: ElementAction,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        self.pending_actions.push_back((id, action));
+        // 正在等待时由已安排的重试按顺序执行
+        if !self.waiting_for_lock {
+            self.run_pending_actions(window, cx);
+        }
+    }
+
+    /// 依次执行等待中的界面事件。ACP 请求正持有事务锁时不阻塞界面线程，
+    /// 报告忙碌并在下一帧重试
+    fn run_pending_actions(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        while let Some((id, action)) = self.pending_actions.front().cloned() {
+            let app_state = &self.app_state;
+            let result = app_state.try_transaction(|| {
+                app_state
+                    .elements()
+                    .perform(id, action, app_state, &CancellationToken::new())
+            });
+            let Some(result) = result else {
+                if !self.waiting_for_lock {
+                    self.waiting_for_lock = true;
+                    println!("正在执行 ACP 请求，界面操作将在请求完成后执行");
+                }
+                cx.on_next_frame(window, |this, window, cx| this.run_pending_actions(window, cx));
+                return;
+            };
+            self.pending_actions.pop_front();
+            if let Err(e) = result {
+                eprintln!("处理界面事件时出错: {}", e);
+            }
+        }
+        self.waiting_for_lock = false;
+        cx.notify();
+    }
+}
+
+/// 注册界面元素的操作处理函数，界面事件和 ACP 请求共用同一个处理函数。
+/// 元素树中每个元素的 actions 就是这里注册的操作
+pub fn register_element_handlers(app_state: &AppState) {
+    let elements = app_state.elements();
+    elements.register_handler(
+        element_ids::CYCLE_COLOR_BUTTON,
+        "click",
+        |state, _action, _token| {
+            let color = state.cycle_bg_color();
+            Ok(format!("已点击按钮，当前背景: {}", color.name()))
+        },
+    );
+    // 视图在界面线程移动 GPUI 焦点，注册表在处理函数成功后记录焦点并推送 focus_changed
+    elements.register_handler(
+        element_ids::CYCLE_COLOR_BUTTON,
+        "focus",
+        |state, _action, _token| {
+            let id = element_ids::CYCLE_COLOR_BUTTON.to_string();
+            state.request_ui_update(UiUpdate::Focus(Some(id)));
+            Ok("按钮已获得焦点".to_string())
+        },
+    );
+    elements.register_handler(
+        element_ids::SHOW_HSL_CHECKBOX,
+        "toggle",
+        |state, _action, _token| {
+            let shown = if state.toggle_show_hsl() { "显示" } else { "隐藏" };
+            Ok(format!("已{} HSL 数值", shown))
+        },
+    );
+    elements.register_handler(
+        element_ids::HUE_SLIDER,
+        "set_value",
+        |state, action, _token| {
+            let ElementAction::SetValue(value) = action else {
+                anyhow::bail!("滑块不支持操作 {}", action.name());
+            };
+            let color = state.set_bg_color(hue_color(parse_hue(value)?));
+            Ok(format!("色相已设置，当前背景: {}", color.name()))
+        },
+    );
+    // 向下滚动降低色相，与常见的滑块一致
+    elements.register_handler(
+        element_ids::HUE_SLIDER,
+        "scroll",
+        |state, action, _token| {
+            let ElementAction::Scroll { dy, .. } = action else {
+                anyhow::bail!("滑块不支持操作 {}", action.name());
+            };
+            let hue = state.get_bg_color().hsl().h - dy * HUE_PER_PIXEL;
+            let color = state.set_bg_color(hue_color(hue));
+            Ok(format!("色相已调整，当前背景: {}", color.name()))
+        },
+    );
+}
+
+/// 按应用状态描述界面的元素树，ACP 请求到达时也用它重建元素树
+fn describe(app_state: &AppState) -> UiElement {
+    UiElement::new(element_ids::ROOT, "window")
+        .with_label("AgentKit 目标应用")
+        .with_children(vec![
+            UiElement::new(element_ids::COLOR_LABEL, "label")
+                .with_label("当前背景")
+                .with_text(color_label_text(app_state)),
+            UiElement::new(element_ids::CYCLE_COLOR_BUTTON, "button")
+                .with_label("循环背景颜色")
+                .with_text(CYCLE_COLOR_BUTTON_TEXT),
+            UiElement::new(element_ids::SHOW_HSL_CHECKBOX, "checkbox")
+                .with_label("显示 HSL 数值")
+                .with_text(if app_state.show_hsl() { "已选中" } else { "未选中" }),
+            UiElement::new(element_ids::HUE_SLIDER, "slider")
+                .with_label("色相")
+                .with_text(hue_slider_text(app_state)),
+        ])
+}
+
+/// 颜色标签的文本，勾选复选框时附带 HSL 数值
+fn color_label_text(app_state: &AppState) -> String {
+    let color = app_state.get_bg_color();
+    if app_state.show_hsl() {
+        let Hsl { h, s, l } = color.hsl();
+        format!("当前背景: {} (HSL {}, {}, {})", color.name(), h, s, l)
+    } else {
+        format!("当前背景: {}", color.name())
+    }
+}
+
+/// 色相滑块的文本
+fn hue_slider_text(app_state: &AppState) -> String {
+    format!("色相: {:.0}°", app_state.get_bg_color().hsl().h)
+}
+
+/// 滑块选择的颜色，饱和度和亮度与浅色预设相同
+fn hue_color(hue: f32) -> BackgroundColor {
+    BackgroundColor::Custom(Hsl {
+        h: hue.rem_euclid(360.0),
+        s: 0.5,
+        l: 0.8,
+    })
+}
+
+/// 解析滑块的值，接受数字或数字文本，AccessKit 的 SetValue 以文本传入
+fn parse_hue(value: &Value) -> anyhow::Result<f32> {
+    let hue = value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|text| text.trim().parse().ok()));
+    match hue {
+        Some(hue) if (0.0..=360.0).contains(&hue) => Ok(hue as f32),
+        _ => anyhow::bail!("色相必须是 0 到 360 之间的数字: {}", value),
+    }
+}
+
+/// 将 GPUI 的位置信息转换为 ACP 格式
+fn to_element_bounds(bounds: &Bounds<Pixels>) -> ElementBounds {
+    ElementBounds {
+        x: f32::from(bounds.origin.x),
+        y: f32::from(bounds.origin.y),
+        width: f32::from(bounds.size.width),
+        height: f32::from(bounds.size.height),
     }
 }
 
 impl Render for RootView {
-    fn render(&mut self, _cx: &mut Window, _view_cx: &mut Context<Self>) -> impl IntoElement {
+    fn render(&mut self, window: &mut Window, view_cx: &mut Context<Self>) -> impl IntoElement {
         let bg_color = self.app_state.get_bg_color();
-        let color_name = bg_color.name();
+        let color_label = color_label_text(&self.app_state);
+        let button_focused = self.button_focus.is_focused(window);
+        let checkbox_mark = if self.app_state.show_hsl() { "[x]" } else { "[ ]" };
+
+        // 同步元素树，供 ACP 查询
+        let app_state = self.app_state.clone();
+        app_state.elements().update_tree(self.describe(), &app_state);
+        let viewport = window.viewport_size();
+        app_state.elements().update_bounds(
+            element_ids::ROOT,
+            ElementBounds {
+                x: 0.0,
+                y: 0.0,
+                width: f32::from(viewport.width),
+                height: f32::from(viewport.height),
+            },
+        );
 
         // 使用Hsla创建颜色
         let white_bg = white(); 
@@ -107,7 +577,7 @@ impl Render for RootView {
         div()
             .size_full()
             .bg(bg_color_value)
-            .children(vec![
+            .child(
                 div()
                     .absolute()
                     .inset_0()
@@ -116,12 +586,29 @@ impl Render for RootView {
                     .items_center()
                     .justify_center()
                     .gap_4()
-                    .children(vec![
+                    // 布局完成后记录子元素位置，顺序与下面的 child 一致
+                    .on_children_prepainted(move |children_bounds, _window, _cx| {
+                        let ids = [
+                            element_ids::COLOR_LABEL,
+                            element_ids::CYCLE_COLOR_BUTTON,
+                            element_ids::SHOW_HSL_CHECKBOX,
+                            element_ids::HUE_SLIDER,
+                        ];
+                        for (id, bounds) in ids.iter().zip(children_bounds.iter()) {
+                            app_state.elements().update_bounds(id, to_element_bounds(bounds));
+                        }
+                    })
+                    .child(
                         div()
+                            .id(element_ids::COLOR_LABEL)
                             .text_xl()
                             .pb_4()
-                            .child(format!("当前背景: {}", color_name)),
+                            .child(color_label),
+                    )
+                    .child(
                         div() // 简化的按钮
+                            .id(element_ids::CYCLE_COLOR_BUTTON)
+                            .track_focus(&self.button_focus)
                             .bg(white_bg)
                             .text_color(black_text)
                             .border_1()
@@ -129,9 +616,48 @@ impl Render for RootView {
                             .rounded_md()
                             .px_4()
                             .py_2()
-                            .child("点击按钮或通过ACP改变颜色"),
-                    ]),
-            ])
+                            // 获得焦点时加粗边框
+                            .when(button_focused, |button| button.border_2())
+                            .on_click(view_cx.listener(|this, _event, window, cx| {
+                                let id = element_ids::CYCLE_COLOR_BUTTON;
+                                this.perform_ui_action(id, ElementAction::Click, window, cx);
+                            }))
+                            .child(CYCLE_COLOR_BUTTON_TEXT),
+                    )
+                    .child(
+                        div() // 简化的复选框
+                            .id(element_ids::SHOW_HSL_CHECKBOX)
+                            .text_color(black_text)
+                            .on_click(view_cx.listener(|this, _event, window, cx| {
+                                let id = element_ids::SHOW_HSL_CHECKBOX;
+                                this.perform_ui_action(id, ElementAction::Toggle, window, cx);
+                            }))
+                            .child(format!("{} 显示 HSL 数值", checkbox_mark)),
+                    )
+                    .child(
+                        div() // 简化的滑块，滚动鼠标滚轮调整色相
+                            .id(element_ids::HUE_SLIDER)
+                            .text_color(black_text)
+                            .border_1()
+                            .border_color(black_border)
+                            .rounded_md()
+                            .px_4()
+                            .py_2()
+                            .on_scroll_wheel(view_cx.listener(
+                                |this, event: &ScrollWheelEvent, window, cx| {
+                                    // GPUI 向上滚动时 y 为正，ACP 的 dy 向下为正
+                                    let delta = event.delta.pixel_delta(window.line_height());
+                                    let action = ElementAction::Scroll {
+                                        dx: -f32::from(delta.x),
+                                        dy: -f32::from(delta.y),
+                                    };
+                                    let id = element_ids::HUE_SLIDER;
+                                    this.perform_ui_action(id, action, window, cx);
+                                },
+                            ))
+                            .child(hue_slider_text(&self.app_state)),
+                    ),
+            )
     }
 }
 
@@ -140,119 +666,142 @@ impl Render for RootView {
 
 impl EventEmitter<DismissEvent> for RootView {}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use element_ids::{CYCLE_COLOR_BUTTON, HUE_SLIDER, SHOW_HSL_CHECKBOX};
+
+    /// 注册了与 `RootView` 相同的处理函数并已渲染的应用状态
+    fn app_state() -> AppState {
+        let app_state = AppState::new();
+        register_element_handlers(&app_state);
+        app_state.elements().set_describer(describe);
+        app_state.elements().update_tree(describe(&app_state), &app_state);
+        app_state
+    }
 
-/// ACP 消息类型
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AcpMessage {
-    #[serde(rename = "type")]
-    pub message_type: String,
-    pub seq_id: u64,
-    pub payload: Value,
-}
-
-/// ACP 响应载荷
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AcpResponsePayload {
-    pub success: bool,
-    pub message: String,
-}
+    fn perform(app_state: &AppState, id: &str, action: ElementAction) -> Result<String, ElementError> {
+        app_state
+            .elements()
+            .perform(id, action, app_state, &CancellationToken::new())
+    }
 
-/// 处理 ACP 连接
-pub fn handle_acp_connection(
-    stream: TcpStream,
-    app_state: Arc<AppState>,
-    _app_context: impl AppContext // 使用trait约束
-) {
-    let mut reader = BufReader::new(stream.try_clone().expect("无法克隆 TCP 流"));
-    let mut writer = stream;
+    fn hue(app_state: &AppState) -> f32 {
+        app_state.get_bg_color().hsl().h
+    }
 
-    let mut line = String::new();
-    if let Err(e) = reader.read_line(&mut line) {
-        eprintln!("读取 ACP 请求时出错: {}", e);
-        return;
+    #[test]
+    fn elements_list_their_registered_actions() {
+        let root = app_state().elements().snapshot().unwrap();
+        let actions = |id: &str| root.find(id).unwrap().actions.clone();
+        assert_eq!(actions(CYCLE_COLOR_BUTTON), ["click", "focus"]);
+        assert_eq!(actions(SHOW_HSL_CHECKBOX), ["toggle"]);
+        assert_eq!(actions(HUE_SLIDER), ["scroll", "set_value"]);
     }
 
-    println!("收到 ACP 请求: {}", line);
+    #[test]
+    fn checkbox_toggles_the_hsl_values() {
+        let app_state = app_state();
+        perform(&app_state, SHOW_HSL_CHECKBOX, ElementAction::Toggle).unwrap();
+        assert!(app_state.show_hsl());
+        app_state.elements().refresh(&app_state);
+        let root = app_state.elements().snapshot().unwrap();
+        assert_eq!(root.find(SHOW_HSL_CHECKBOX).unwrap().text.as_deref(), Some("已选中"));
+        assert!(root.find(element_ids::COLOR_LABEL).unwrap().text.as_ref().unwrap().contains("HSL"));
+
+        perform(&app_state, SHOW_HSL_CHECKBOX, ElementAction::Toggle).unwrap();
+        assert!(!app_state.show_hsl());
+    }
 
-    // 解析 ACP 消息
-    let acp_message: AcpMessage = match serde_json::from_str(&line) {
-        Ok(msg) => msg,
-        Err(e) => {
-            eprintln!("解析 ACP 消息时出错: {}", e);
-            return;
+    #[test]
+    fn slider_sets_and_scrolls_the_hue() {
+        let app_state = app_state();
+        perform(&app_state, HUE_SLIDER, ElementAction::SetValue(json!(200))).unwrap();
+        assert_eq!(hue(&app_state), 200.0);
+        // AccessKit 以文本传入数值
+        perform(&app_state, HUE_SLIDER, ElementAction::SetValue(json!(" 90 "))).unwrap();
+        assert_eq!(hue(&app_state), 90.0);
+
+        for value in [json!(400), json!("blue"), json!(null)] {
+            let result = perform(&app_state, HUE_SLIDER, ElementAction::SetValue(value.clone()));
+            assert!(matches!(result, Err(ElementError::Failed(_))), "{}", value);
         }
-    };
+        assert_eq!(hue(&app_state), 90.0);
 
-    // 获取序列 ID 用于响应
-    let seq_id = acp_message.seq_id;
-
-    // 检查是否是请求类型
-    if acp_message.message_type != "request" {
-        send_error_response(&mut writer, seq_id, "不支持的消息类型");
-        return;
+        // 向下滚动降低色相，越过 0 度后回到 360 度附近
+        perform(&app_state, HUE_SLIDER, ElementAction::Scroll { dx: 0.0, dy: 40.0 }).unwrap();
+        assert_eq!(hue(&app_state), 80.0);
+        perform(&app_state, HUE_SLIDER, ElementAction::Scroll { dx: 0.0, dy: 400.0 }).unwrap();
+        assert_eq!(hue(&app_state), 340.0);
     }
 
-    // 解析 payload 中的 action 和 command_name
-    let action = match acp_message.payload.get("action") {
-        Some(Value::String(action)) => action.as_str(),
-        _ => {
-            send_error_response(&mut writer, seq_id, "缺少 action 字段");
-            return;
-        }
-    };
-
-    // 检查 action 类型
-    if action != "custom_command" {
-        send_error_response(&mut writer, seq_id, "不支持的 action 类型");
-        return;
+    #[test]
+    fn focus_is_moved_by_the_view() {
+        let app_state = app_state();
+        let mut updates = app_state.ui_updates();
+        let before = app_state.snapshot();
+
+        perform(&app_state, CYCLE_COLOR_BUTTON, ElementAction::Focus).unwrap();
+        assert_eq!(app_state.elements().focused().as_deref(), Some(CYCLE_COLOR_BUTTON));
+        assert_eq!(
+            updates.try_recv().unwrap(),
+            UiUpdate::Focus(Some(CYCLE_COLOR_BUTTON.to_string()))
+        );
+
+        // 回滚同样移动窗口中的焦点
+        app_state.restore(&before);
+        assert_eq!(app_state.elements().focused(), None);
+        assert_eq!(updates.try_recv().unwrap(), UiUpdate::Focus(None));
+        assert!(updates.try_recv().is_err());
     }
 
-    // 获取 command_name
-    let command_name = match acp_message.payload.get("command_name") {
-        Some(Value::String(cmd)) => cmd.as_str(),
-        _ => {
-            send_error_response(&mut writer, seq_id, "缺少 command_name 字段");
-            return;
-        }
-    };
+    #[test]
+    fn state_changes_redraw_the_window() {
+        let app_state = app_state();
+        let mut updates = app_state.ui_updates();
 
-    // 处理 CYCLE_COLOR 命令
-    if command_name == "CYCLE_COLOR" {
-        // 直接更改应用状态，不再派发操作
+        // 与 ACP 命令一样直接修改状态，不经过界面事件
         app_state.cycle_bg_color();
+        app_state.set_show_hsl(true);
+        assert_eq!(updates.try_recv().unwrap(), UiUpdate::Redraw);
+        assert_eq!(updates.try_recv().unwrap(), UiUpdate::Redraw);
+
+        // 没有变化时不需要重绘
+        app_state.set_show_hsl(true);
+        app_state.set_bg_color(app_state.get_bg_color());
+        assert!(updates.try_recv().is_err());
+    }
 
-        // 发送成功响应
-        let response = AcpMessage {
-            message_type: "response".to_string(),
-            seq_id,
-            payload: serde_json::to_value(AcpResponsePayload {
-                success: true,
-                message: "颜色已通过 ACP 循环".to_string(),
+    #[test]
+    fn restore_covers_the_checkbox() {
+        let app_state = app_state();
+        let before = app_state.snapshot();
+        perform(&app_state, SHOW_HSL_CHECKBOX, ElementAction::Toggle).unwrap();
+        perform(&app_state, HUE_SLIDER, ElementAction::SetValue(json!(30))).unwrap();
+        app_state.restore(&before);
+        assert!(!app_state.show_hsl());
+        assert_eq!(app_state.get_bg_color(), BackgroundColor::White);
+    }
+
+    #[test]
+    fn try_transaction_does_not_wait_for_acp_requests() {
+        let app_state = Arc::new(app_state());
+        let (locked_tx, locked) = std::sync::mpsc::channel();
+        let (release_tx, release) = std::sync::mpsc::channel::<()>();
+        let holder = {
+            let app_state = app_state.clone();
+            std::thread::spawn(move || {
+                app_state.transaction(|| {
+                    locked_tx.send(()).unwrap();
+                    let _ = release.recv();
+                })
             })
-            .unwrap(),
         };
 
-        if let Err(e) = writeln!(writer, "{}", serde_json::to_string(&response).unwrap()) {
-            eprintln!("发送 ACP 响应时出错: {}", e);
-        }
-    } else {
-        send_error_response(&mut writer, seq_id, "未知命令");
+        locked.recv().unwrap();
+        assert_eq!(app_state.try_transaction(|| ()), None);
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(app_state.try_transaction(|| 1), Some(1));
     }
 }
-
-/// 发送错误响应
-pub fn send_error_response(writer: &mut TcpStream, seq_id: u64, error_message: &str) {
-    let response = AcpMessage {
-        message_type: "response".to_string(),
-        seq_id,
-        payload: serde_json::to_value(AcpResponsePayload {
-            success: false,
-            message: format!("错误: {}", error_message),
-        })
-        .unwrap(),
-    };
-
-    if let Err(e) = writeln!(writer, "{}", serde_json::to_string(&response).unwrap()) {
-        eprintln!("发送错误响应时出错: {}", e);
-    }
-}
\ No newline at end of file
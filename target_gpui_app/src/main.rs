@@ -1,38 +1,191 @@
-use gpui::{Application, App, Window};
-use std::{sync::Arc, thread, net::TcpListener};
-use target_gpui_app::{AppState, RootView, handle_acp_connection};
+use acp::{discovery, EventPayload, DEFAULT_TCP_ADDR};
+use gpui::{AppContext, Application};
+use serde_json::{json, Value};
+use std::{env, io::ErrorKind, process, sync::Arc};
+use target_gpui_app::{
+    default_unix_socket_path, AcpServer, AcpServerConfig, AppState, BackgroundColor, Hsl, RootView,
+};
+
+/// 注册应用通过 ACP 暴露的命令
+fn register_commands(app_state: &AppState) {
+    app_state.commands().register(
+        "CYCLE_COLOR",
+        "循环切换背景颜色: 白色 -> 浅蓝 -> 浅绿",
+        json!({ "type": "object", "properties": {} }),
+        |state, _params, _token| {
+            let color = state.cycle_bg_color();
+            Ok(format!("颜色已通过 ACP 循环，当前背景: {}", color.name()))
+        },
+    );
+
+    let presets: Vec<&str> = BackgroundColor::PRESETS
+        .iter()
+        .filter_map(BackgroundColor::preset_name)
+        .collect();
+    app_state.commands().register(
+        "SET_BG_COLOR",
+        "把背景设置为指定的颜色: 用 color 指定预设颜色，或用 hsl 指定任意颜色，二者只能提供一个",
+        json!({
+            "type": "object",
+            "properties": {
+                "color": {
+                    "type": "string",
+                    "enum": presets,
+                    "description": "预设颜色: white 白色、light_blue 浅蓝、light_green 浅绿"
+                },
+                "hsl": {
+                    "type": "object",
+                    "description": "任意颜色",
+                    "properties": {
+                        "h": { "type": "number", "minimum": 0, "maximum": 360, "description": "色相，单位为度" },
+                        "s": { "type": "number", "minimum": 0, "maximum": 1, "description": "饱和度" },
+                        "l": { "type": "number", "minimum": 0, "maximum": 1, "description": "亮度" }
+                    },
+                    "required": ["h", "s", "l"],
+                    "additionalProperties": false
+                }
+            },
+            "additionalProperties": false,
+            "minProperties": 1,
+            "maxProperties": 1
+        }),
+        |state, params, _token| {
+            let color = state.set_bg_color(color_from_params(params)?);
+            Ok(format!("背景已设置为: {}", color.name()))
+        },
+    );
+}
+
+/// 解析 `SET_BG_COLOR` 的参数，参数已经通过了 Schema 的校验
+fn color_from_params(params: Option<Value>) -> anyhow::Result<BackgroundColor> {
+    let params = params.unwrap_or_default();
+    if let Some(name) = params.get("color").and_then(Value::as_str) {
+        return BackgroundColor::from_preset_name(name)
+            .ok_or_else(|| anyhow::anyhow!("未知的预设颜色: {}", name));
+    }
+    let hsl: Hsl = serde_json::from_value(params["hsl"].clone())?;
+    Ok(BackgroundColor::Custom(hsl))
+}
+
+/// 读取命令行参数 `--<名称> <值>` 或 `--<名称>=<值>`，未提供时读取环境变量
+fn arg_or_env(name: &str, var: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    env::var(var).ok()
+}
+
+/// 命令行中是否有开关 `--<名称>`
+fn has_flag(name: &str) -> bool {
+    let flag = format!("--{}", name);
+    env::args().skip(1).any(|arg| arg == flag)
+}
+
+/// 生成本次启动的认证令牌，256 位随机数的十六进制表示
+fn generate_auth_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// 启动 ACP 服务器。未指定 TCP 地址且默认端口被占用时（例如同时运行多个实例），
+/// 改为监听系统分配的端口，客户端通过发现文件找到它
+fn start_acp_server(app_state: Arc<AppState>) -> AcpServer {
+    let explicit_addr = arg_or_env("acp-addr", "ACP_ADDR");
+    let tcp = !has_flag("no-acp-tcp");
+    if !tcp && explicit_addr.is_some() {
+        eprintln!("--no-acp-tcp 不能与 --acp-addr 或 ACP_ADDR 同时使用");
+        process::exit(1);
+    }
+    let unix_socket = if has_flag("acp-unix") {
+        match default_unix_socket_path() {
+            Some(path) => Some(path),
+            None => {
+                eprintln!("--acp-unix 需要设置 XDG_RUNTIME_DIR，并且只能在类 Unix 系统上使用");
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let config = AcpServerConfig {
+        addr: tcp.then(|| explicit_addr.clone().unwrap_or_else(|| DEFAULT_TCP_ADDR.to_string())),
+        ws_addr: arg_or_env("acp-ws-addr", "ACP_WS_ADDR"),
+        ws_allowed_origins: arg_or_env("acp-ws-origins", "ACP_WS_ORIGINS")
+            .map(|origins| {
+                origins
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        unix_socket,
+        discovery_dir: Some(discovery::discovery_dir()),
+        auth_token: Some(generate_auth_token()),
+        ..AcpServerConfig::default()
+    };
+
+    let result = match AcpServer::start(config.clone(), app_state.clone()) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse && tcp && explicit_addr.is_none() => {
+            println!("默认地址 {} 已被占用，改用系统分配的端口", DEFAULT_TCP_ADDR);
+            let config = AcpServerConfig {
+                addr: Some("127.0.0.1:0".to_string()),
+                ..config
+            };
+            AcpServer::start(config, app_state)
+        }
+        result => result,
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("无法启动 ACP 服务器: {}", e);
+        process::exit(1);
+    })
+}
 
 fn main() {
     Application::new().run(|cx| {
         let app_state = Arc::new(AppState::new());
-        let app_state_clone_for_tcp = app_state.clone();
+        register_commands(&app_state);
 
         // 创建窗口和根视图
-        cx.open_window(
-            Default::default(),
-            |cx_window, _| {
-                RootView::new(app_state.clone(), cx_window)
-            },
-        );
-
-        // 启动 TCP 服务器线程处理 ACP 请求
-        let cx_handle = cx.focus_handle();
-
-        thread::spawn(move || {
-            let listener = TcpListener::bind("127.0.0.1:7880").expect("无法绑定到端口 7880");
-            println!("ACP 服务器已启动在 127.0.0.1:7880");
-
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        println!("新的 ACP 连接已建立");
-                        handle_acp_connection(stream, app_state_clone_for_tcp.clone(), cx_handle.clone());
-                    }
-                    Err(e) => {
-                        eprintln!("接受连接时出错: {}", e);
-                    }
-                }
-            }
-        });
+        cx.open_window(Default::default(), |window, cx| {
+            cx.new(|cx| RootView::new(app_state.clone(), window, cx))
+        })
+        .expect("无法创建窗口");
+
+        // 窗口关闭时通知订阅的客户端
+        let events_state = app_state.clone();
+        cx.on_window_closed(move |_| {
+            events_state.events().emit(EventPayload::WindowClosed);
+        })
+        .detach();
+
+        // 启动 ACP 服务器，每个连接由独立线程处理。
+        // TCP 地址由 --acp-addr 或 ACP_ADDR 指定，端口为 0 时由系统分配，--no-acp-tcp 关闭 TCP；
+        // 指定 --acp-unix 时在 $XDG_RUNTIME_DIR 下为本实例创建只有当前用户可以访问的 Unix 套接字，
+        // 指定 --acp-ws-addr 或 ACP_WS_ADDR 时同时在该地址上提供 WebSocket，
+        // 网页只有在来源列于 --acp-ws-origins 或 ACP_WS_ORIGINS（逗号分隔）中时才能连接。
+        // 三种传输都没有启用时启动失败。
+        // 实际监听的地址写入发现目录，agentkit_layer 据此列出运行中的应用。
+        // 每次启动生成新的认证令牌，写入发现目录中只有当前用户可以读取的文件，客户端须在握手时出示；
+        // 令牌文件无法写入时启动失败，不会在客户端拿不到令牌的情况下运行
+        let mut acp_server = start_acp_server(app_state.clone());
+
+        // 应用退出时关闭 ACP 服务器
+        cx.on_app_quit(move |_| {
+            acp_server.shutdown();
+            async {}
+        })
+        .detach();
     });
 }
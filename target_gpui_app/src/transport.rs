@@ -0,0 +1,204 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+};
+
+/// ACP 连接使用的字节流
+#[derive(Debug)]
+pub enum AcpStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AcpStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            AcpStream::Tcp(stream) => stream.try_clone().map(AcpStream::Tcp),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.try_clone().map(AcpStream::Unix),
+        }
+    }
+
+    /// 关闭连接的读写两端
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            AcpStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            AcpStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            AcpStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for AcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            AcpStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for AcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AcpStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AcpStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            AcpStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// ACP 监听器，以非阻塞模式接受连接
+pub(crate) enum AcpListener {
+    Tcp(TcpListener),
+    /// 在 TCP 上进行 WebSocket 握手，每个文本帧是一条消息
+    WebSocket(TcpListener),
+    #[cfg(unix)]
+    Unix { listener: UnixListener, path: PathBuf },
+}
+
+impl AcpListener {
+    pub(crate) fn bind_tcp(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(AcpListener::Tcp(listener))
+    }
+
+    pub(crate) fn bind_ws(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(AcpListener::WebSocket(listener))
+    }
+
+    /// 是否需要按 WebSocket 处理接受的连接
+    pub(crate) fn is_websocket(&self) -> bool {
+        matches!(self, AcpListener::WebSocket(_))
+    }
+
+    /// 绑定 Unix 套接字，套接字文件只有当前用户可以访问
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        }
+        // 同一路径的套接字文件只可能是之前的实例遗留的
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(AcpListener::Unix {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn bind_unix(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "当前平台不支持 Unix 套接字",
+        ))
+    }
+
+    /// 接受一个连接，返回阻塞模式的连接和对端的描述
+    pub(crate) fn accept(&self) -> io::Result<(AcpStream, String)> {
+        let (stream, peer) = match self {
+            AcpListener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                (AcpStream::Tcp(stream), peer_addr.to_string())
+            }
+            AcpListener::WebSocket(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                (AcpStream::Tcp(stream), format!("ws://{}", peer_addr))
+            }
+            #[cfg(unix)]
+            AcpListener::Unix { listener, path } => {
+                let (stream, _) = listener.accept()?;
+                (AcpStream::Unix(stream), format!("unix:{}", path.display()))
+            }
+        };
+        // 监听器是非阻塞的，连接本身需要恢复为阻塞模式
+        stream.set_nonblocking(false)?;
+        Ok((stream, peer))
+    }
+
+    /// 监听地址的描述
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            AcpListener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "未知地址".to_string()),
+            AcpListener::WebSocket(listener) => listener
+                .local_addr()
+                .map(|addr| format!("ws://{}", addr))
+                .unwrap_or_else(|_| "未知地址".to_string()),
+            #[cfg(unix)]
+            AcpListener::Unix { path, .. } => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for AcpListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let AcpListener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// 本实例默认的 Unix 套接字路径 `$XDG_RUNTIME_DIR/agentkit/<应用名>-<pid>.sock`，
+/// 未设置 `XDG_RUNTIME_DIR` 或平台不支持时为 `None`
+pub fn default_unix_socket_path() -> Option<PathBuf> {
+    if !cfg!(unix) {
+        return None;
+    }
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    Some(
+        PathBuf::from(runtime_dir)
+            .join("agentkit")
+            .join(format!("{}-{}.sock", env!("CARGO_PKG_NAME"), std::process::id())),
+    )
+}
@@ -0,0 +1,144 @@
+use crate::{
+    acp_server::{error_message, Dispatcher, Session, MAX_MESSAGE_BYTES},
+    transport::AcpStream,
+    AppState,
+};
+use acp::ErrorCode;
+use std::{
+    io::ErrorKind,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header::ORIGIN, StatusCode},
+    protocol::WebSocketConfig,
+    Error as WsError, Message,
+};
+
+/// 读取的轮询间隔，每次轮询后发送排队的响应和事件
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 握手请求的 `Origin` 是否允许连接。浏览器中的网页发起连接时总会带上 `Origin`，
+/// 不在允许列表中的来源被拒绝；不带 `Origin` 的本地客户端不受影响
+fn origin_allowed(origin: Option<&str>, allowed_origins: &[String]) -> bool {
+    match origin {
+        None | Some("") => true,
+        Some(origin) => allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+    }
+}
+
+/// 处理 WebSocket 上的 ACP 连接，每个文本帧是一条不带换行符的消息。
+/// WebSocket 无法在两个线程间拆分读写，因此在同一个线程中交替读取和发送
+pub(crate) fn handle_ws_connection(
+    stream: AcpStream,
+    session: Session,
+    app_state: Arc<AppState>,
+    idle_timeout: Duration,
+    allowed_origins: &[String],
+) {
+    // 与按行读取的连接使用相同的单条消息上限
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_BYTES as usize),
+        max_frame_size: Some(MAX_MESSAGE_BYTES as usize),
+        ..WebSocketConfig::default()
+    };
+
+    // 回调的签名由 tungstenite 规定
+    #[allow(clippy::result_large_err)]
+    let check_origin = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        let origin = request.headers().get(ORIGIN).map(|value| value.to_str().unwrap_or("?"));
+        if origin_allowed(origin, allowed_origins) {
+            return Ok(response);
+        }
+        eprintln!("拒绝来源 {} 的 WebSocket 连接", origin.unwrap_or_default());
+        let mut rejection = ErrorResponse::new(Some("不允许的来源".to_string()));
+        *rejection.status_mut() = StatusCode::FORBIDDEN;
+        Err(rejection)
+    };
+
+    let mut socket = match tungstenite::accept_hdr_with_config(stream, check_origin, Some(config)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("WebSocket 握手失败: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+        eprintln!("配置 WebSocket 连接时出错: {}", e);
+        return;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut dispatcher = Dispatcher::new(session, sender.clone(), app_state);
+    let mut last_activity = Instant::now();
+
+    'connection: loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                last_activity = Instant::now();
+                dispatcher.handle_message(&text);
+            }
+            Ok(Message::Binary(_)) => {
+                last_activity = Instant::now();
+                let _ = sender.send(error_message(
+                    0,
+                    ErrorCode::InvalidRequest,
+                    "ACP 消息必须使用文本帧".to_string(),
+                ));
+            }
+            Ok(Message::Close(_)) => break,
+            // ping 由 tungstenite 自动应答
+            Ok(_) => last_activity = Instant::now(),
+            Err(WsError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if last_activity.elapsed() >= idle_timeout {
+                    println!("ACP 连接空闲超时");
+                    break;
+                }
+            }
+            Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => break,
+            Err(e) => {
+                eprintln!("读取 WebSocket 消息时出错: {}", e);
+                break;
+            }
+        }
+
+        // 发送排队的响应和事件，每条消息一个文本帧
+        while let Ok(message) = receiver.try_recv() {
+            let text = match serde_json::to_string(&message) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("序列化 ACP 消息时出错: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = socket.send(Message::Text(text)) {
+                eprintln!("发送 WebSocket 消息时出错: {}", e);
+                break 'connection;
+            }
+        }
+    }
+
+    dispatcher.close();
+    let _ = socket.close(None);
+    let _ = socket.flush();
+    let _ = socket.get_ref().shutdown();
+    println!("ACP WebSocket 连接已关闭");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        let allowed = vec!["http://localhost:3000".to_string()];
+        assert!(origin_allowed(None, &[]));
+        assert!(origin_allowed(Some(""), &[]));
+        assert!(origin_allowed(Some("http://localhost:3000"), &allowed));
+        assert!(origin_allowed(Some("HTTP://LOCALHOST:3000"), &allowed));
+        assert!(!origin_allowed(Some("http://localhost:3000"), &[]));
+        assert!(!origin_allowed(Some("https://example.com"), &allowed));
+        assert!(!origin_allowed(Some("null"), &allowed));
+    }
+}